@@ -0,0 +1,310 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use actix_web::error::ErrorBadRequest;
+use actix_web::http::header::HeaderMap;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use sha2::{Sha256, Sha512};
+
+use crate::error::MyError;
+
+/// Digest algorithms a client can send a checksum in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    /// Parses an algorithm name of `Digest` (RFC 3230) or `Repr-Digest`
+    /// (RFC 9530), `None` for algorithms that aren't supported.
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "md5" => Some(Self::Md5),
+            "sha" => Some(Self::Sha1),
+            "sha-256" => Some(Self::Sha256),
+            "sha-512" => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha",
+            Self::Sha256 => "sha-256",
+            Self::Sha512 => "sha-512",
+        }
+    }
+}
+
+/// Headers a client can send a checksum in.
+pub(crate) const HEADERS: [&str; 5] = [
+    "Repr-Digest",
+    "Content-Digest",
+    "Digest",
+    "Content-MD5",
+    "X-Content-SHA256",
+];
+
+/// A checksum sent by the client.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Expected {
+    pub algorithm: Algorithm,
+    pub digest: Vec<u8>,
+    /// Header the checksum was sent in.
+    pub header: &'static str,
+}
+
+/// Reads the checksums in `Content-MD5`, `Repr-Digest`, `Content-Digest`, `Digest`
+/// and `X-Content-SHA256`, rejecting malformed headers with `400 Bad Request`.
+pub(crate) fn from_headers(headers: &HeaderMap) -> Result<Vec<Expected>, MyError> {
+    let mut expected = Vec::new();
+    for header in ["Repr-Digest", "Content-Digest", "Digest"] {
+        for value in headers.get_all(header) {
+            let value = value.to_str().map_err(|_| invalid(header))?;
+            for (algorithm, digest) in parse_digest(header, value)? {
+                expected.push(Expected {
+                    algorithm,
+                    digest,
+                    header,
+                });
+            }
+        }
+    }
+    if let Some(value) = headers.get("Content-MD5") {
+        let value = value.to_str().map_err(|_| invalid("Content-MD5"))?;
+        expected.push(Expected {
+            algorithm: Algorithm::Md5,
+            digest: decode(value.trim(), 16).ok_or_else(|| invalid("Content-MD5"))?,
+            header: "Content-MD5",
+        });
+    }
+    if let Some(value) = headers.get("X-Content-SHA256") {
+        let value = value
+            .to_str()
+            .map_err(|_| invalid("X-Content-SHA256"))?
+            .trim();
+        // hex, or base64 like the other headers
+        let digest = hex::decode(value)
+            .ok()
+            .filter(|a| a.len() == 32)
+            .or_else(|| decode(value, 32))
+            .ok_or_else(|| invalid("X-Content-SHA256"))?;
+        expected.push(Expected {
+            algorithm: Algorithm::Sha256,
+            digest,
+            header: "X-Content-SHA256",
+        });
+    }
+    Ok(expected)
+}
+
+/// Parses a `Repr-Digest` or `Content-Digest` dictionary, e.g.
+/// `sha-256=:X48E9q...=:`, or a `Digest` list, e.g. `SHA-256=X48E9q...=`.
+/// Unsupported algorithms are skipped.
+fn parse_digest(header: &'static str, value: &str) -> Result<Vec<(Algorithm, Vec<u8>)>, MyError> {
+    let structured = header != "Digest";
+    let mut digests = Vec::new();
+    for member in value.split(',').filter(|a| !a.trim().is_empty()) {
+        let (name, value) = member.split_once('=').ok_or_else(|| invalid(header))?;
+        let Some(algorithm) = Algorithm::parse(name) else {
+            continue;
+        };
+        // parameters of dictionary members are ignored
+        let value = value.split(';').next().unwrap_or_default().trim();
+        let value = if structured {
+            value
+                .strip_prefix(':')
+                .and_then(|a| a.strip_suffix(':'))
+                .ok_or_else(|| invalid(header))?
+        } else {
+            value
+        };
+        let len = match algorithm {
+            Algorithm::Md5 => 16,
+            Algorithm::Sha1 => 20,
+            Algorithm::Sha256 => 32,
+            Algorithm::Sha512 => 64,
+        };
+        digests.push((
+            algorithm,
+            decode(value, len).ok_or_else(|| invalid(header))?,
+        ));
+    }
+    Ok(digests)
+}
+
+fn decode(value: &str, len: usize) -> Option<Vec<u8>> {
+    STANDARD.decode(value).ok().filter(|a| a.len() == len)
+}
+
+fn invalid(header: &str) -> MyError {
+    ErrorBadRequest(format!("Invalid {} header", header)).into()
+}
+
+/// Computes the digests of a stream as it is received and compares them with the
+/// checksums sent by the client.
+pub(crate) struct Verifier {
+    expected: Vec<Expected>,
+    md5: Option<Md5>,
+    sha1: Option<Sha1>,
+    sha256: Option<Sha256>,
+    sha512: Option<Sha512>,
+}
+
+impl Verifier {
+    pub(crate) fn new(expected: Vec<Expected>) -> Self {
+        let uses = |algorithm| expected.iter().any(|a| a.algorithm == algorithm);
+        Self {
+            md5: uses(Algorithm::Md5).then(Md5::new),
+            sha1: uses(Algorithm::Sha1).then(Sha1::new),
+            sha256: uses(Algorithm::Sha256).then(Sha256::new),
+            sha512: uses(Algorithm::Sha512).then(Sha512::new),
+            expected,
+        }
+    }
+
+    /// A verifier that checks nothing.
+    pub(crate) fn none() -> Self {
+        Self::new(Vec::new())
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        if let Some(md5) = &mut self.md5 {
+            md5.update(data);
+        }
+        if let Some(sha1) = &mut self.sha1 {
+            sha1.update(data);
+        }
+        if let Some(sha256) = &mut self.sha256 {
+            sha256.update(data);
+        }
+        if let Some(sha512) = &mut self.sha512 {
+            sha512.update(data);
+        }
+    }
+
+    /// Rejects the stream with `400 Bad Request` when a digest doesn't match its
+    /// checksum.
+    pub(crate) fn verify(self) -> Result<(), MyError> {
+        let md5 = self.md5.map(|a| a.finalize().to_vec());
+        let sha1 = self.sha1.map(|a| a.finalize().to_vec());
+        let sha256 = self.sha256.map(|a| a.finalize().to_vec());
+        let sha512 = self.sha512.map(|a| a.finalize().to_vec());
+        for expected in &self.expected {
+            let actual = match expected.algorithm {
+                Algorithm::Md5 => &md5,
+                Algorithm::Sha1 => &sha1,
+                Algorithm::Sha256 => &sha256,
+                Algorithm::Sha512 => &sha512,
+            };
+            if actual.as_ref() != Some(&expected.digest) {
+                return Err(ErrorBadRequest(format!(
+                    "Checksum mismatch: {} {} is {} but the received data is {}",
+                    expected.header,
+                    expected.algorithm.name(),
+                    STANDARD.encode(&expected.digest),
+                    actual
+                        .as_deref()
+                        .map(|a| STANDARD.encode(a))
+                        .unwrap_or_default(),
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        map
+    }
+
+    fn verify(headers: &HeaderMap, data: &[u8]) -> Result<(), MyError> {
+        let mut verifier = Verifier::new(from_headers(headers)?);
+        verifier.update(data);
+        verifier.verify()
+    }
+
+    #[test]
+    fn test_from_headers() {
+        let expected = from_headers(&headers(&[
+            (
+                "repr-digest",
+                "sha-256=:ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=:, unixsum=:MTIz:",
+            ),
+            (
+                "digest",
+                "MD5=kAFQmDzST7DWlj99KOF/cg==, SHA=qZk+NkcGgWq6PiVxeFDCbJzQ2J0=",
+            ),
+        ]))
+        .unwrap();
+        assert_eq!(
+            expected
+                .iter()
+                .map(|a| (a.header, a.algorithm))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Repr-Digest", Algorithm::Sha256),
+                ("Digest", Algorithm::Md5),
+                ("Digest", Algorithm::Sha1),
+            ]
+        );
+
+        assert!(from_headers(&headers(&[("repr-digest", "sha-256=abc")])).is_err());
+        assert!(from_headers(&headers(&[("content-md5", "abc")])).is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        // digests of "abc"
+        for header in [
+            ("content-md5", "kAFQmDzST7DWlj99KOF/cg=="),
+            ("repr-digest", "sha-256=:ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=:"),
+            ("digest", "SHA=qZk+NkcGgWq6PiVxeFDCbJzQ2J0="),
+            (
+                "x-content-sha256",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                "content-digest",
+                "sha-512=:3a81oZNherrMQXNJriBBMRLm+k6JqX6iCp7u5ktV05ohkpkqJ0/BqDa6PCOj/uu9RU1EI2Q86A4qmslPpUyknw==:",
+            ),
+        ] {
+            let headers = headers(&[header]);
+            assert!(verify(&headers, b"abc").is_ok(), "{:?}", header);
+            assert!(verify(&headers, b"abd").is_err(), "{:?}", header);
+        }
+        assert!(verify(&HeaderMap::new(), b"abc").is_ok());
+    }
+}
@@ -0,0 +1,208 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use log::warn;
+use std::env;
+
+/// Default maximum size of a single uploaded file.
+pub(crate) const DEFAULT_MAX_SIZE: usize = 20 * 1024 * 1024; // 20mb
+
+/// Gets a setting for an output directory.
+///
+/// The per directory variable `{name}_{dir_index}` takes precedence over the
+/// global `{name}` variable.
+///
+/// # Examples
+///
+/// ```
+/// // MAX_SIZE_2=5M overrides MAX_SIZE=20M for uploads with `X-Dir-Index: 2`
+/// let max_size = dir_var("MAX_SIZE", Some("2"));
+/// ```
+pub(crate) fn dir_var(name: &str, dir_index: Option<&str>) -> Option<String> {
+    dir_index
+        .and_then(|index| env::var(format!("{}_{}", name, index)).ok())
+        .or_else(|| env::var(name).ok())
+        .filter(|a| !a.trim().is_empty())
+}
+
+/// Gets a boolean setting for an output directory, `true`, `yes` and `1` are
+/// truthy.
+pub(crate) fn dir_flag(name: &str, dir_index: Option<&str>) -> bool {
+    dir_var(name, dir_index)
+        .map(|a| matches!(a.trim().to_ascii_lowercase().as_str(), "true" | "yes" | "1"))
+        .unwrap_or(false)
+}
+
+/// Lists the configured output directories, `OUT_DIR` and every `OUT_DIR_{index}`,
+/// with their index.
+pub(crate) fn out_dirs() -> Vec<(String, Option<String>)> {
+    env::vars()
+        .filter_map(|(key, value)| {
+            if key == "OUT_DIR" {
+                Some((value, None))
+            } else {
+                key.strip_prefix("OUT_DIR_")
+                    .map(|index| (value, Some(index.to_owned())))
+            }
+        })
+        .collect()
+}
+
+/// Path of the output directory with the index `dir_index`, `OUT_DIR` without one.
+pub(crate) fn out_dir(dir_index: Option<&str>) -> Option<String> {
+    match dir_index {
+        Some(index) => env::var(format!("OUT_DIR_{}", index)).ok(),
+        None => env::var("OUT_DIR").ok(),
+    }
+}
+
+/// Parses a human readable size such as `512`, `100K`, `10MB` or `1G`.
+///
+/// Units are binary, i.e. `1K` is 1024 bytes.
+pub(crate) fn parse_size(input: &str) -> Option<usize> {
+    let input = input.trim().to_ascii_uppercase();
+    let input = input.strip_suffix('B').unwrap_or(&input);
+    let (number, multiplier) = match input.chars().last()? {
+        'K' => (&input[..input.len() - 1], 1024),
+        'M' => (&input[..input.len() - 1], 1024 * 1024),
+        'G' => (&input[..input.len() - 1], 1024 * 1024 * 1024),
+        _ => (input, 1),
+    };
+    number
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+}
+
+/// Formats a size in bytes using the largest unit it is a whole multiple of.
+pub(crate) fn format_size(size: usize) -> String {
+    const UNITS: [(usize, &str); 3] = [
+        (1024 * 1024 * 1024, "GB"),
+        (1024 * 1024, "MB"),
+        (1024, "KB"),
+    ];
+    for (unit, name) in UNITS {
+        if size >= unit && size % unit == 0 {
+            return format!("{} {}", size / unit, name);
+        }
+    }
+    format!("{} bytes", size)
+}
+
+/// Size limits of an output directory.
+#[derive(Debug, Clone)]
+pub(crate) struct SizeLimits {
+    /// Limit for files that don't match any of `by_type`.
+    pub default: usize,
+    /// Limits per MIME type (`application/pdf`) or family (`image`).
+    pub by_type: Vec<(String, usize)>,
+}
+
+impl SizeLimits {
+    /// Reads the limits of an output directory from `MAX_SIZE` and
+    /// `MAX_SIZE_BY_TYPE`, e.g. `MAX_SIZE_BY_TYPE=image:10M,application/pdf:50M`.
+    pub(crate) fn from_env(dir_index: Option<&str>) -> Self {
+        let default = dir_var("MAX_SIZE", dir_index)
+            .and_then(|a| {
+                parse_size(&a).or_else(|| {
+                    warn!("Invalid MAX_SIZE: {}", a);
+                    None
+                })
+            })
+            .unwrap_or(DEFAULT_MAX_SIZE);
+        let by_type = dir_var("MAX_SIZE_BY_TYPE", dir_index)
+            .map(|a| Self::parse_by_type(&a))
+            .unwrap_or_default();
+
+        Self { default, by_type }
+    }
+
+    fn parse_by_type(list: &str) -> Vec<(String, usize)> {
+        list.split(',')
+            .filter(|a| !a.trim().is_empty())
+            .filter_map(|rule| {
+                let parsed = rule.rsplit_once(':').and_then(|(mime, size)| {
+                    let mime = mime.trim().trim_end_matches("/*").to_ascii_lowercase();
+                    parse_size(size).map(|size| (mime, size))
+                });
+                if parsed.is_none() {
+                    warn!("Invalid MAX_SIZE_BY_TYPE rule: {}", rule);
+                }
+                parsed
+            })
+            .collect()
+    }
+
+    /// Returns the limit for a file of the given MIME type, an exact type match wins
+    /// over a family match.
+    pub(crate) fn for_mime(&self, mime_type: Option<&mime_guess::Mime>) -> usize {
+        let mime_type = match mime_type {
+            Some(mime_type) => mime_type,
+            None => return self.default,
+        };
+        let essence = mime_type.essence_str().to_ascii_lowercase();
+        let family = mime_type.type_().as_str().to_ascii_lowercase();
+
+        self.by_type
+            .iter()
+            .find(|(mime, _)| *mime == essence)
+            .or_else(|| self.by_type.iter().find(|(mime, _)| *mime == family))
+            .map(|(_, size)| *size)
+            .unwrap_or(self.default)
+    }
+
+    /// Returns the largest limit of any type, used to reject requests by their
+    /// `Content-Length` before the type of the file is known.
+    pub(crate) fn max(&self) -> usize {
+        self.by_type
+            .iter()
+            .map(|(_, size)| *size)
+            .fold(self.default, usize::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("100K"), Some(100 * 1024));
+        assert_eq!(parse_size("10mb"), Some(10 * 1024 * 1024));
+        assert_eq!(parse_size(" 1G "), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("ten"), None);
+        assert_eq!(format_size(20 * 1024 * 1024), "20 MB");
+        assert_eq!(format_size(1000), "1000 bytes");
+    }
+
+    #[test]
+    fn test_size_limits_for_mime() {
+        let limits = SizeLimits {
+            default: 100,
+            by_type: SizeLimits::parse_by_type("image/*:10, image/gif:20,application/pdf:50"),
+        };
+        assert_eq!(limits.for_mime(Some(&"image/png".parse().unwrap())), 10);
+        assert_eq!(limits.for_mime(Some(&"image/gif".parse().unwrap())), 20);
+        assert_eq!(
+            limits.for_mime(Some(&"application/pdf".parse().unwrap())),
+            50
+        );
+        assert_eq!(limits.for_mime(Some(&"video/mp4".parse().unwrap())), 100);
+        assert_eq!(limits.for_mime(None), 100);
+        assert_eq!(limits.max(), 100);
+    }
+}
@@ -63,7 +63,7 @@ mod tests {
 
     #[test]
     fn test_sha1_hash() {
-        let mut file = File::open("IMG_9211.jpg").unwrap();
+        let mut file = File::open("img/IMG_9211.jpg").unwrap();
         let hash = get_sha1_file(&mut file).unwrap();
         assert_eq!(hash, "e1586b201c06a2d440358378f15d6a7987ee4ab6");
     }
@@ -0,0 +1,119 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use actix_web::web::Bytes;
+use base64::engine::general_purpose::STANDARD;
+use base64::{DecodeError, Engine};
+
+/// Number of base64 characters decoded at once, must be a multiple of 4.
+const CHUNK_CHARS: usize = 64 * 1024;
+
+/// Splits a `data:` URI into its MIME type and base64 payload.
+///
+/// Plain base64 strings are returned as is with no MIME type. Only base64
+/// encoded data URIs are supported.
+///
+/// # Examples
+///
+/// ```
+/// let (mime, data) = parse_data_uri("data:image/png;base64,iVBORw0K").unwrap();
+/// assert_eq!(mime, Some("image/png"));
+/// assert_eq!(data, "iVBORw0K");
+/// ```
+pub(crate) fn parse_data_uri(input: &str) -> Result<(Option<&str>, &str), &'static str> {
+    let input = input.trim();
+    let rest = match input.strip_prefix("data:") {
+        Some(rest) => rest,
+        None => return Ok((None, input)),
+    };
+
+    let (meta, data) = rest.split_once(',').ok_or("Invalid data URI.")?;
+    let mut params = meta.split(';');
+    let mime = params.next().filter(|m| !m.is_empty());
+
+    if !params.any(|p| p.eq_ignore_ascii_case("base64")) {
+        return Err("Data URI must be base64 encoded.");
+    }
+
+    Ok((mime, data))
+}
+
+/// Lazily decodes a base64 string in fixed size chunks, so the decoded data can
+/// be streamed to disk without holding a second full copy in memory.
+///
+/// ASCII whitespace (e.g. line breaks of MIME encoded data) is skipped.
+pub(crate) struct Base64Chunks<'a> {
+    data: std::slice::Iter<'a, u8>,
+    buf: Vec<u8>,
+}
+
+impl<'a> Base64Chunks<'a> {
+    pub(crate) fn new(data: &'a str) -> Self {
+        Self {
+            data: data.as_bytes().iter(),
+            buf: Vec::with_capacity(CHUNK_CHARS),
+        }
+    }
+}
+
+impl<'a> Iterator for Base64Chunks<'a> {
+    type Item = Result<Bytes, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buf.clear();
+        for c in self.data.by_ref() {
+            if c.is_ascii_whitespace() {
+                continue;
+            }
+            self.buf.push(*c);
+            if self.buf.len() == CHUNK_CHARS {
+                break;
+            }
+        }
+        if self.buf.is_empty() {
+            return None;
+        }
+        Some(STANDARD.decode(&self.buf).map(Bytes::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_data_uri() {
+        let (mime, data) = parse_data_uri("data:image/png;base64,aGVsbG8=").unwrap();
+        assert_eq!(mime, Some("image/png"));
+        assert_eq!(data, "aGVsbG8=");
+
+        assert_eq!(parse_data_uri("aGVsbG8=").unwrap(), (None, "aGVsbG8="));
+        assert!(parse_data_uri("data:text/plain,hello").is_err());
+    }
+
+    #[test]
+    fn test_base64_chunks() {
+        let raw: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut encoded = STANDARD.encode(&raw);
+        encoded.insert(100, '\n');
+
+        let decoded: Vec<u8> = Base64Chunks::new(&encoded)
+            .map(|c| c.unwrap())
+            .flat_map(|c| c.to_vec())
+            .collect();
+        assert_eq!(decoded, raw);
+        assert!(Base64Chunks::new("not base64!").any(|c| c.is_err()));
+    }
+}
@@ -1,4 +1,5 @@
 use actix_web::http::header::ToStrError;
+use actix_web::http::StatusCode;
 
 use actix_web::{error, HttpResponse, ResponseError};
 use anyhow::{anyhow, Error as AnyhowError};
@@ -19,6 +20,22 @@ impl std::fmt::Display for MyError {
     }
 }
 
+/// Error message that keeps the HTTP status code of the actix error it was
+/// converted from.
+#[derive(Debug)]
+struct StatusError {
+    status: StatusCode,
+    message: String,
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
 impl From<AnyhowError> for MyError {
     fn from(error: AnyhowError) -> Self {
         Self(error)
@@ -27,28 +44,42 @@ impl From<AnyhowError> for MyError {
 
 impl From<io::Error> for MyError {
     fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::StorageFull {
+            return Self(AnyhowError::new(StatusError {
+                status: StatusCode::INSUFFICIENT_STORAGE,
+                message: error.to_string(),
+            }));
+        }
         Self(anyhow!("{}", error))
     }
 }
 
 impl<T> From<actix_web::error::InternalError<T>> for MyError
 where
-    T: std::fmt::Debug + std::fmt::Display,
+    T: std::fmt::Debug + std::fmt::Display + 'static,
 {
     fn from(error: actix_web::error::InternalError<T>) -> Self {
-        Self(anyhow!("{}", error))
+        Self(AnyhowError::new(StatusError {
+            status: error.status_code(),
+            message: error.to_string(),
+        }))
     }
 }
 
 impl From<actix_web::error::Error> for MyError {
     fn from(error: actix_web::error::Error) -> Self {
-        Self(anyhow!("{}", error))
+        Self(AnyhowError::new(StatusError {
+            status: error.as_response_error().status_code(),
+            message: error.to_string(),
+        }))
     }
 }
 
 impl ResponseError for MyError {
     fn error_response(&self) -> HttpResponse {
-        let status_code = if self.0.is::<std::io::Error>() {
+        let status_code = if let Some(error) = self.0.downcast_ref::<StatusError>() {
+            error.status
+        } else if self.0.is::<std::io::Error>() {
             actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
         } else if self.0.is::<serde_json::Error>() {
             actix_web::http::StatusCode::BAD_REQUEST
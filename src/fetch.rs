@@ -0,0 +1,290 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use actix_web::error::ErrorBadRequest;
+use anyhow::anyhow;
+use ipnet::IpNet;
+use log::debug;
+use reqwest::{redirect, Client, Response, Url};
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use crate::error::MyError;
+
+/// Networks a remote fetch is never allowed to connect to, unless explicitly
+/// allowed with `FETCH_ALLOW_NETWORKS`.
+const BLOCKED_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Limits applied to server side fetches of remote URLs.
+pub(crate) struct FetchConfig {
+    pub timeout: Duration,
+    pub max_redirects: usize,
+    pub allow_networks: Vec<IpNet>,
+}
+
+impl FetchConfig {
+    /// Reads the fetch limits from `FETCH_TIMEOUT` (seconds), `FETCH_MAX_REDIRECTS`
+    /// and `FETCH_ALLOW_NETWORKS` (comma separated IPs or CIDRs).
+    pub(crate) fn from_env() -> Self {
+        let timeout = env::var("FETCH_TIMEOUT")
+            .ok()
+            .and_then(|a| a.parse().ok())
+            .unwrap_or(30);
+        let max_redirects = env::var("FETCH_MAX_REDIRECTS")
+            .ok()
+            .and_then(|a| a.parse().ok())
+            .unwrap_or(3);
+        let allow_networks = env::var("FETCH_ALLOW_NETWORKS")
+            .map(|a| parse_networks(&a))
+            .unwrap_or_default();
+
+        Self {
+            timeout: Duration::from_secs(timeout),
+            max_redirects,
+            allow_networks,
+        }
+    }
+}
+
+/// Parses a comma separated list of networks, a bare IP is treated as a single
+/// host network.
+fn parse_networks(list: &str) -> Vec<IpNet> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .filter_map(|a| {
+            a.parse::<IpNet>()
+                .or_else(|_| a.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| log::warn!("Ignoring invalid network in FETCH_ALLOW_NETWORKS: {}", a))
+                .ok()
+        })
+        .collect()
+}
+
+/// Checks whether `ip` may be connected to, i.e. it is a public address or it is
+/// inside one of the allowed networks.
+pub(crate) fn is_ip_allowed(ip: IpAddr, allow_networks: &[IpNet]) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        _ => ip,
+    };
+
+    if allow_networks.iter().any(|net| net.contains(&ip)) {
+        return true;
+    }
+
+    !BLOCKED_NETWORKS
+        .iter()
+        .filter_map(|net| net.parse::<IpNet>().ok())
+        .any(|net| net.contains(&ip))
+}
+
+/// Resolves the host of `url` and makes sure every address it points to is allowed,
+/// so a DNS record cannot smuggle a private address past the check.
+async fn resolve_allowed(url: &Url, config: &FetchConfig) -> Result<Vec<SocketAddr>, MyError> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| ErrorBadRequest("URL has no host."))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| ErrorBadRequest("URL has no port."))?;
+
+    // `host_str` keeps the brackets of IPv6 literals
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|_| ErrorBadRequest(format!("Cannot resolve host: {}", host)))?
+        .collect();
+
+    if addrs.is_empty() {
+        return Err(ErrorBadRequest(format!("Cannot resolve host: {}", host)).into());
+    }
+
+    if let Some(addr) = addrs
+        .iter()
+        .find(|addr| !is_ip_allowed(addr.ip(), &config.allow_networks))
+    {
+        debug!("blocked fetch of {} resolved to {}", url, addr);
+        return Err(ErrorBadRequest("URL points to a forbidden address.").into());
+    }
+
+    Ok(addrs)
+}
+
+/// Downloads `url`, following at most `max_redirects` redirects.
+///
+/// Every hop is resolved and checked against the blocked networks and the
+/// connection is pinned to the checked addresses. Returns the final URL together
+/// with the response, whose body is not read yet.
+pub(crate) async fn fetch(url: &str, config: &FetchConfig) -> Result<(Url, Response), MyError> {
+    let mut url = Url::parse(url).map_err(|_| ErrorBadRequest("Invalid URL."))?;
+
+    for _ in 0..=config.max_redirects {
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(ErrorBadRequest("Only http and https URLs are supported.").into());
+        }
+
+        let addrs = resolve_allowed(&url, config).await?;
+        let host = url.host_str().unwrap_or_default().to_owned();
+
+        let client = Client::builder()
+            .redirect(redirect::Policy::none())
+            .no_proxy()
+            .timeout(config.timeout)
+            .resolve_to_addrs(&host, &addrs)
+            .build()
+            .map_err(|e| anyhow!(e))?;
+
+        debug!("fetching: {}", url);
+
+        let response = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| ErrorBadRequest(format!("Fetch failed: {}", e)))?;
+
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|a| a.to_str().ok())
+                .ok_or_else(|| ErrorBadRequest("Redirect without location."))?;
+            url = url
+                .join(location)
+                .map_err(|_| ErrorBadRequest("Invalid redirect location."))?;
+            continue;
+        }
+
+        if !response.status().is_success() {
+            return Err(ErrorBadRequest(format!(
+                "Remote server responded with {}",
+                response.status()
+            ))
+            .into());
+        }
+
+        return Ok((url, response));
+    }
+
+    Err(ErrorBadRequest("Too many redirects.").into())
+}
+
+/// Returns the last path segment of `url` when it looks like a filename.
+pub(crate) fn filename_from_url(url: &Url) -> Option<String> {
+    url.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| name.contains('.'))
+        .map(|name| name.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    fn config(allow_networks: &str) -> FetchConfig {
+        FetchConfig {
+            timeout: Duration::from_secs(5),
+            max_redirects: 2,
+            allow_networks: parse_networks(allow_networks),
+        }
+    }
+
+    /// Starts a local HTTP stand-in for a remote server.
+    fn serve() -> SocketAddr {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route(
+                    "/a.txt",
+                    web::get().to(|| async { HttpResponse::Ok().body("hello") }),
+                )
+                .route(
+                    "/redirect/{n}",
+                    web::get().to(|path: web::Path<u32>| async move {
+                        let n = path.into_inner();
+                        let location = if n == 0 {
+                            "/a.txt".to_string()
+                        } else {
+                            format!("/redirect/{}", n - 1)
+                        };
+                        HttpResponse::Found()
+                            .insert_header(("Location", location))
+                            .finish()
+                    }),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        actix_rt::spawn(server.run());
+        addr
+    }
+
+    #[test]
+    fn test_is_ip_allowed() {
+        let none = [];
+        assert!(is_ip_allowed("8.8.8.8".parse().unwrap(), &none));
+        assert!(!is_ip_allowed("127.0.0.1".parse().unwrap(), &none));
+        assert!(!is_ip_allowed("10.1.2.3".parse().unwrap(), &none));
+        assert!(!is_ip_allowed("169.254.169.254".parse().unwrap(), &none));
+        assert!(!is_ip_allowed("::1".parse().unwrap(), &none));
+        assert!(!is_ip_allowed("::ffff:192.168.1.1".parse().unwrap(), &none));
+
+        let allow = parse_networks("10.0.0.0/8, 127.0.0.1");
+        assert!(is_ip_allowed("10.1.2.3".parse().unwrap(), &allow));
+        assert!(is_ip_allowed("127.0.0.1".parse().unwrap(), &allow));
+        assert!(!is_ip_allowed("127.0.0.2".parse().unwrap(), &allow));
+    }
+
+    #[actix_rt::test]
+    async fn test_fetch() {
+        let addr = serve();
+        let url = format!("http://{}/redirect/1", addr);
+
+        assert!(fetch(&url, &config("")).await.is_err());
+
+        let (final_url, response) = fetch(&url, &config("127.0.0.1")).await.unwrap();
+        assert_eq!(filename_from_url(&final_url).as_deref(), Some("a.txt"));
+        assert_eq!(response.text().await.unwrap(), "hello");
+
+        let url = format!("http://{}/redirect/5", addr);
+        assert!(fetch(&url, &config("127.0.0.1")).await.is_err());
+        assert!(fetch("file:///etc/passwd", &config("")).await.is_err());
+    }
+}
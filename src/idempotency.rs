@@ -0,0 +1,526 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use actix_web::error::{
+    ErrorBadRequest, ErrorConflict, ErrorServiceUnavailable, ErrorUnprocessableEntity,
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::checksum;
+use crate::crypto;
+use crate::error::{ApiResult, MyError};
+
+/// Default time the response of a request is replayed for.
+const DEFAULT_TTL: u64 = 24 * 3600;
+
+/// Default number of keys kept.
+const DEFAULT_MAX_KEYS: usize = 10_000;
+
+/// Longest accepted `Idempotency-Key`.
+const MAX_KEY_LEN: usize = 255;
+
+/// Default header identifying the client of a request.
+const DEFAULT_CLIENT_HEADER: &str = "X-Client-Id";
+
+/// Header with the signature of the client id.
+const CLIENT_SIGNATURE_HEADER: &str = "X-Client-Signature";
+
+/// Header identifying the client of a request from `IDEMPOTENCY_CLIENT_HEADER`,
+/// the peer address is used without it.
+pub(crate) fn client_header() -> String {
+    env::var("IDEMPOTENCY_CLIENT_HEADER").unwrap_or_else(|_| DEFAULT_CLIENT_HEADER.into())
+}
+
+enum Entry {
+    /// The first request with the key is still being processed.
+    InProgress { fingerprint: String },
+    Done {
+        fingerprint: String,
+        response: serde_json::Value,
+        expires_at: Instant,
+    },
+}
+
+impl Entry {
+    fn fingerprint(&self) -> &str {
+        match self {
+            Entry::InProgress { fingerprint } | Entry::Done { fingerprint, .. } => fingerprint,
+        }
+    }
+}
+
+/// Responses of requests sent with an `Idempotency-Key` header, kept in memory and
+/// shared by the workers.
+pub(crate) struct Idempotency {
+    ttl: Duration,
+    /// Most keys kept, the responses that expire first make room for new keys.
+    max_keys: usize,
+    /// Header identifying the client, the peer address is used without it.
+    client_header: String,
+    /// Key the client id is signed with.
+    secret_key: String,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl Idempotency {
+    pub(crate) fn new(
+        ttl: Duration,
+        max_keys: usize,
+        client_header: &str,
+        secret_key: &str,
+    ) -> Self {
+        Self {
+            ttl,
+            max_keys,
+            client_header: client_header.to_owned(),
+            secret_key: secret_key.to_owned(),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reads the settings from `IDEMPOTENCY_TTL` (seconds),
+    /// `IDEMPOTENCY_MAX_KEYS` and `IDEMPOTENCY_CLIENT_HEADER`, client ids are
+    /// signed with `secret_key`.
+    pub(crate) fn from_env(secret_key: &str) -> Self {
+        let number = |name: &str, default| {
+            env::var(name)
+                .ok()
+                .and_then(|a| {
+                    a.trim().parse().ok().or_else(|| {
+                        warn!("Invalid {}: {}", name, a);
+                        None
+                    })
+                })
+                .unwrap_or(default)
+        };
+        Self::new(
+            Duration::from_secs(number("IDEMPOTENCY_TTL", DEFAULT_TTL as usize) as u64),
+            number("IDEMPOTENCY_MAX_KEYS", DEFAULT_MAX_KEYS),
+            &client_header(),
+            secret_key,
+        )
+    }
+
+    /// The client of a request: the id in the client header when it comes with a
+    /// valid `X-Client-Signature`, so it can't be spoofed, or the peer address.
+    fn client(&self, req: &HttpRequest) -> Result<String, MyError> {
+        let headers = req.headers();
+        let Some(client) = headers.get(self.client_header.as_str()) else {
+            return Ok(req
+                .peer_addr()
+                .map(|a| a.ip().to_string())
+                .unwrap_or_default());
+        };
+        let client = client.to_str().unwrap_or_default();
+        let signature = headers
+            .get(CLIENT_SIGNATURE_HEADER)
+            .and_then(|a| a.to_str().ok())
+            .unwrap_or_default();
+        let message = format!("client:{}", client);
+        if client.is_empty()
+            || !crypto::verify_signature(self.secret_key.as_bytes(), message.as_bytes(), signature)
+        {
+            return Err(ErrorBadRequest(format!(
+                "Invalid {} or {} header",
+                self.client_header, CLIENT_SIGNATURE_HEADER
+            ))
+            .into());
+        }
+        Ok(format!("id:{}", client))
+    }
+
+    /// The key of a request scoped to its client, endpoint and output directory,
+    /// `None` without an `Idempotency-Key` header.
+    fn scoped_key(&self, req: &HttpRequest) -> Result<Option<String>, MyError> {
+        let key = match req.headers().get("Idempotency-Key") {
+            Some(key) => key
+                .to_str()
+                .ok()
+                .map(str::trim)
+                .filter(|a| !a.is_empty() && a.len() <= MAX_KEY_LEN)
+                .ok_or_else(|| ErrorBadRequest("Invalid Idempotency-Key header"))?,
+            None => return Ok(None),
+        };
+        let client = self.client(req)?;
+        let dir_index = req
+            .headers()
+            .get("X-Dir-Index")
+            .and_then(|a| a.to_str().ok())
+            .unwrap_or_default();
+        Ok(Some(format!(
+            "{}\n{}\n{}\n{}",
+            client,
+            req.path(),
+            dir_index,
+            key
+        )))
+    }
+}
+
+/// Removes the entry of a request that didn't complete, including when it is
+/// dropped because the client disconnected, so it can be retried.
+struct InProgress<'a> {
+    idempotency: &'a Idempotency,
+    key: Option<String>,
+}
+
+impl InProgress<'_> {
+    fn complete(mut self, response: &serde_json::Value) {
+        if let Some(key) = self.key.take() {
+            let mut entries = self.idempotency.entries.lock().unwrap();
+            let fingerprint = match entries.remove(&key) {
+                Some(entry) => entry.fingerprint().to_owned(),
+                None => return,
+            };
+            let entry = Entry::Done {
+                fingerprint,
+                response: response.clone(),
+                expires_at: Instant::now() + self.idempotency.ttl,
+            };
+            entries.insert(key, entry);
+        }
+    }
+}
+
+impl Drop for InProgress<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.idempotency.entries.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+/// Fingerprint of a request to its endpoint: the content type without its
+/// parameters, the length, the checksum headers and `body`, the fields of a JSON
+/// request. The content of streamed uploads is only covered by their checksums.
+pub(crate) fn fingerprint(req: &HttpRequest, body: &[&str]) -> String {
+    let headers = req.headers();
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|a| a.to_str().ok())
+            .unwrap_or_default()
+    };
+    let mut sha256 = Sha256::new();
+    let content_type = header("Content-Type").split(';').next().unwrap_or_default();
+    for part in [content_type, header("Content-Length")] {
+        sha256.update(part.trim().as_bytes());
+        sha256.update(b"\n");
+    }
+    for name in checksum::HEADERS {
+        for value in headers.get_all(name) {
+            sha256.update(value.as_bytes());
+            sha256.update(b"\n");
+        }
+    }
+    for field in body {
+        sha256.update(field.as_bytes());
+        sha256.update(b"\n");
+    }
+    hex::encode(sha256.finalize())
+}
+
+/// Runs `handler` for a request, replaying the response of an earlier successful
+/// request with the same `Idempotency-Key` instead. A request sent while one with
+/// the same key is still processed is rejected with `409 Conflict`, and one whose
+/// `fingerprint` differs from the first with `422 Unprocessable Entity`.
+pub(crate) async fn idempotent<F>(
+    idempotency: &web::Data<Idempotency>,
+    req: &HttpRequest,
+    fingerprint: String,
+    handler: F,
+) -> ApiResult
+where
+    F: Future<Output = Result<serde_json::Value, MyError>>,
+{
+    let key = idempotency.scoped_key(req)?;
+    if let Some(key) = &key {
+        let mut entries = idempotency.entries.lock().unwrap();
+        let now = Instant::now();
+        entries.retain(|_, entry| match entry {
+            Entry::InProgress { .. } => true,
+            Entry::Done { expires_at, .. } => *expires_at > now,
+        });
+        if entries
+            .get(key)
+            .is_some_and(|entry| entry.fingerprint() != fingerprint)
+        {
+            return Err(ErrorUnprocessableEntity(
+                "Idempotency-Key was used for a different request",
+            )
+            .into());
+        }
+        match entries.get(key) {
+            Some(Entry::InProgress { .. }) => {
+                return Err(
+                    ErrorConflict("A request with this Idempotency-Key is in progress").into(),
+                )
+            }
+            Some(Entry::Done { response, .. }) => {
+                debug!("replaying response for idempotency key");
+                return Ok(HttpResponse::Ok()
+                    .insert_header(("Idempotent-Replayed", "true"))
+                    .json(response));
+            }
+            None => {
+                if entries.len() >= idempotency.max_keys {
+                    // make room by dropping the response that expires first
+                    let oldest = entries
+                        .iter()
+                        .filter_map(|(key, entry)| match entry {
+                            Entry::Done { expires_at, .. } => Some((*expires_at, key.clone())),
+                            Entry::InProgress { .. } => None,
+                        })
+                        .min();
+                    match oldest {
+                        Some((_, oldest)) => {
+                            entries.remove(&oldest);
+                        }
+                        None => {
+                            return Err(ErrorServiceUnavailable(
+                                "Too many requests with an Idempotency-Key in progress",
+                            )
+                            .into())
+                        }
+                    }
+                }
+                entries.insert(key.clone(), Entry::InProgress { fingerprint });
+            }
+        }
+    }
+
+    let in_progress = InProgress { idempotency, key };
+    let response = handler.await?;
+    in_progress.complete(&response);
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use actix_web::ResponseError;
+    use futures::channel::oneshot;
+    use futures::FutureExt;
+    use serde_json::json;
+
+    const SECRET_KEY: &str = "secret";
+
+    fn request(key: &str, client: &str) -> HttpRequest {
+        let signature = crypto::sign_message(
+            SECRET_KEY.as_bytes(),
+            format!("client:{}", client).as_bytes(),
+        );
+        TestRequest::post()
+            .uri("/upload")
+            .insert_header(("Idempotency-Key", key))
+            .insert_header(("X-Client-Id", client))
+            .insert_header(("X-Client-Signature", signature))
+            .to_http_request()
+    }
+
+    fn idempotency(max_keys: usize) -> web::Data<Idempotency> {
+        web::Data::new(Idempotency::new(
+            Duration::from_secs(60),
+            max_keys,
+            "X-Client-Id",
+            SECRET_KEY,
+        ))
+    }
+
+    async fn body(response: HttpResponse) -> serde_json::Value {
+        serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_idempotent() {
+        let idempotency = idempotency(100);
+
+        let response = idempotent(&idempotency, &request("a", "1"), "f".to_owned(), async {
+            Ok(json!({"n": 1}))
+        })
+        .await
+        .unwrap();
+        assert!(response.headers().get("Idempotent-Replayed").is_none());
+        assert_eq!(body(response).await, json!({"n": 1}));
+
+        // replayed, for the same client only
+        let response = idempotent(&idempotency, &request("a", "1"), "f".to_owned(), async {
+            Ok(json!({"n": 2}))
+        })
+        .await
+        .unwrap();
+        assert!(response.headers().get("Idempotent-Replayed").is_some());
+        assert_eq!(body(response).await, json!({"n": 1}));
+        let response = idempotent(&idempotency, &request("a", "2"), "f".to_owned(), async {
+            Ok(json!({"n": 3}))
+        })
+        .await
+        .unwrap();
+        assert_eq!(body(response).await, json!({"n": 3}));
+
+        // failures aren't kept
+        let failed = idempotent(&idempotency, &request("b", "1"), "f".to_owned(), async {
+            Err(ErrorBadRequest("failed").into())
+        })
+        .await;
+        assert!(failed.is_err());
+        let response = idempotent(&idempotency, &request("b", "1"), "f".to_owned(), async {
+            Ok(json!({"n": 4}))
+        })
+        .await
+        .unwrap();
+        assert_eq!(body(response).await, json!({"n": 4}));
+
+        // concurrent duplicates
+        let (tx, rx) = oneshot::channel::<()>();
+        let req = request("c", "1");
+        let mut first = Box::pin(idempotent(&idempotency, &req, "f".to_owned(), async move {
+            rx.await.unwrap();
+            Ok(json!({"n": 5}))
+        }));
+        assert!((&mut first).now_or_never().is_none());
+        let conflict = idempotent(&idempotency, &request("c", "1"), "f".to_owned(), async {
+            Ok(json!({"n": 6}))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(conflict.error_response().status(), StatusCode::CONFLICT);
+        tx.send(()).unwrap();
+        assert_eq!(body(first.await.unwrap()).await, json!({"n": 5}));
+
+        // dropped while in progress
+        let req = request("d", "1");
+        let pending = idempotent(
+            &idempotency,
+            &req,
+            "f".to_owned(),
+            futures::future::pending(),
+        );
+        assert!(Box::pin(pending).now_or_never().is_none());
+        let response = idempotent(&idempotency, &request("d", "1"), "f".to_owned(), async {
+            Ok(json!({"n": 7}))
+        })
+        .await
+        .unwrap();
+        assert_eq!(body(response).await, json!({"n": 7}));
+    }
+
+    #[actix_rt::test]
+    async fn test_idempotent_fingerprint() {
+        let idempotency = idempotency(100);
+        idempotent(&idempotency, &request("a", "1"), "f".to_owned(), async {
+            Ok(json!({"n": 1}))
+        })
+        .await
+        .unwrap();
+        let error = idempotent(&idempotency, &request("a", "1"), "g".to_owned(), async {
+            Ok(json!({"n": 2}))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error.error_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+
+        let req = |content_type: &str, checksum: &str| {
+            TestRequest::post()
+                .insert_header(("Content-Type", content_type))
+                .insert_header(("Content-Length", "10"))
+                .insert_header(("Content-MD5", checksum))
+                .to_http_request()
+        };
+        let a = fingerprint(&req("multipart/form-data; boundary=a", "x"), &[]);
+        assert_eq!(
+            a,
+            fingerprint(&req("multipart/form-data; boundary=b", "x"), &[])
+        );
+        assert_ne!(
+            a,
+            fingerprint(&req("multipart/form-data; boundary=a", "y"), &[])
+        );
+        assert_ne!(
+            a,
+            fingerprint(&req("multipart/form-data; boundary=a", "x"), &["url"])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_idempotent_client() {
+        let idempotency = idempotency(100);
+        let unsigned = TestRequest::post()
+            .insert_header(("Idempotency-Key", "a"))
+            .insert_header(("X-Client-Id", "1"))
+            .to_http_request();
+        let error = idempotent(&idempotency, &unsigned, "f".to_owned(), async {
+            Ok(json!({}))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_response().status(), StatusCode::BAD_REQUEST);
+        let forged = TestRequest::post()
+            .insert_header(("Idempotency-Key", "a"))
+            .insert_header(("X-Client-Id", "1"))
+            .insert_header(("X-Client-Signature", "00"))
+            .to_http_request();
+        assert!(idempotency.scoped_key(&forged).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_idempotent_max_keys() {
+        let idempotency = idempotency(2);
+        for key in ["a", "b", "c"] {
+            idempotent(&idempotency, &request(key, "1"), "f".to_owned(), async {
+                Ok(json!({ "key": key }))
+            })
+            .await
+            .unwrap();
+        }
+        // the oldest response made room
+        {
+            let entries = idempotency.entries.lock().unwrap();
+            assert_eq!(entries.len(), 2);
+            assert!(!entries.keys().any(|a| a.ends_with("\na")));
+        }
+
+        // no room while every key is in progress
+        let (a, b) = (request("d", "1"), request("e", "1"));
+        let first = idempotent(&idempotency, &a, "f".to_owned(), futures::future::pending());
+        let second = idempotent(&idempotency, &b, "f".to_owned(), futures::future::pending());
+        let mut first = Box::pin(first);
+        let mut second = Box::pin(second);
+        assert!((&mut first).now_or_never().is_none());
+        assert!((&mut second).now_or_never().is_none());
+        let error = idempotent(&idempotency, &request("f", "1"), "f".to_owned(), async {
+            Ok(json!({}))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(
+            error.error_response().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
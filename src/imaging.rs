@@ -0,0 +1,1069 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use actix_web::error::ErrorBadRequest;
+use image::codecs::bmp::BmpDecoder;
+use image::codecs::ico::IcoDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::tiff::TiffDecoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::io::{Limits, Reader};
+use image::{ColorType, DynamicImage, GenericImageView, ImageDecoder, ImageFormat};
+use log::{debug, warn};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::config::{dir_flag, dir_var};
+use crate::error::{img_error, MyError};
+use crate::metadata;
+
+/// Image formats that can be accepted, with their name, stored extension and MIME type.
+const SUPPORTED_FORMATS: &[(ImageFormat, &str, &str, &str)] = &[
+    (ImageFormat::Png, "png", "png", "image/png"),
+    (ImageFormat::Jpeg, "jpeg", "jpg", "image/jpeg"),
+    (ImageFormat::Gif, "gif", "gif", "image/gif"),
+    (ImageFormat::WebP, "webp", "webp", "image/webp"),
+    (ImageFormat::Avif, "avif", "avif", "image/avif"),
+    (ImageFormat::Tiff, "tiff", "tiff", "image/tiff"),
+    (ImageFormat::Bmp, "bmp", "bmp", "image/bmp"),
+    (ImageFormat::Ico, "ico", "ico", "image/x-icon"),
+];
+
+/// Number of bytes needed to detect the format of an image.
+pub(crate) const HEAD_LEN: usize = 32;
+
+/// Number of bytes at the start of an image that are checked for markup, browsers
+/// sniff about this much when deciding to render a file as HTML.
+const SNIFF_LEN: usize = 1024;
+
+/// Markup that has no business in an image and turns it into a polyglot file.
+const MARKUP_SIGNATURES: &[&[u8]] = &[
+    b"<html", b"<script", b"<body", b"<?php", b"<svg", b"<iframe",
+];
+
+/// Dimension limits an image must satisfy to be accepted.
+#[derive(Debug, Clone)]
+pub(crate) struct ImageLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_width: 16384,
+            max_height: 16384,
+            max_pixels: 50_000_000,
+        }
+    }
+}
+
+impl ImageLimits {
+    /// Reads the limits of an output directory from `IMAGE_MAX_WIDTH`,
+    /// `IMAGE_MAX_HEIGHT` and `IMAGE_MAX_PIXELS`.
+    pub(crate) fn from_env(dir_index: Option<&str>) -> Self {
+        let default = Self::default();
+        Self {
+            max_width: dir_var("IMAGE_MAX_WIDTH", dir_index)
+                .and_then(|a| a.parse().ok())
+                .unwrap_or(default.max_width),
+            max_height: dir_var("IMAGE_MAX_HEIGHT", dir_index)
+                .and_then(|a| a.parse().ok())
+                .unwrap_or(default.max_height),
+            max_pixels: dir_var("IMAGE_MAX_PIXELS", dir_index)
+                .and_then(|a| a.parse().ok())
+                .unwrap_or(default.max_pixels),
+        }
+    }
+}
+
+/// Returns the name of an image format as reported in responses, e.g. `jpeg`.
+pub(crate) fn format_name(format: ImageFormat) -> &'static str {
+    SUPPORTED_FORMATS
+        .iter()
+        .find(|(f, ..)| *f == format)
+        .map_or("unknown", |(_, name, ..)| name)
+}
+
+/// Returns the extension an image of `format` is stored with.
+pub(crate) fn format_extension(format: ImageFormat) -> &'static str {
+    SUPPORTED_FORMATS
+        .iter()
+        .find(|(f, ..)| *f == format)
+        .map_or("bin", |(_, _, ext, _)| ext)
+}
+
+/// Returns the MIME type of an image `format`.
+pub(crate) fn format_mime_type(format: ImageFormat) -> &'static str {
+    SUPPORTED_FORMATS
+        .iter()
+        .find(|(f, ..)| *f == format)
+        .map_or("application/octet-stream", |(.., mime)| mime)
+}
+
+/// Reads the image formats accepted by an output directory from `IMAGE_FORMATS`,
+/// e.g. `IMAGE_FORMATS=png,jpeg,webp`. All supported formats are accepted by default.
+pub(crate) fn accepted_formats_from_env(dir_index: Option<&str>) -> Vec<ImageFormat> {
+    match dir_var("IMAGE_FORMATS", dir_index) {
+        Some(list) => parse_formats(&list),
+        None => SUPPORTED_FORMATS.iter().map(|(f, ..)| *f).collect(),
+    }
+}
+
+fn parse_formats(list: &str) -> Vec<ImageFormat> {
+    list.split(',')
+        .map(|a| a.trim().to_ascii_lowercase())
+        .filter(|a| !a.is_empty())
+        .filter_map(|name| {
+            let format = SUPPORTED_FORMATS
+                .iter()
+                .find(|(_, n, ext, _)| *n == name || *ext == name)
+                .map(|(f, ..)| *f);
+            if format.is_none() {
+                warn!(
+                    "Ignoring unsupported image format in IMAGE_FORMATS: {}",
+                    name
+                );
+            }
+            format
+        })
+        .collect()
+}
+
+/// Detects the format of an image from its first bytes and checks that it is one
+/// of the `accepted` formats.
+pub(crate) fn detect_format(head: &[u8], accepted: &[ImageFormat]) -> Result<ImageFormat, MyError> {
+    let format = match image::guess_format(head) {
+        Ok(format) => format,
+        // the image crate only knows a few ftyp box sizes
+        Err(_) if is_avif(head) => ImageFormat::Avif,
+        Err(e) => return Err(img_error(e).into()),
+    };
+    debug!("image format: {:?}", format);
+
+    if !accepted.contains(&format) {
+        let names: Vec<&str> = accepted.iter().map(|f| format_name(*f)).collect();
+        return Err(ErrorBadRequest(format!(
+            "Invalid file format. Must be one of: {}.",
+            names.join(", ")
+        ))
+        .into());
+    }
+
+    Ok(format)
+}
+
+fn is_avif(head: &[u8]) -> bool {
+    head.len() >= 12 && &head[4..8] == b"ftyp" && matches!(&head[8..12], b"avif" | b"avis")
+}
+
+/// Fully decodes the image at `path` to make sure it is what it claims to be.
+///
+/// The dimensions are checked from the header before decoding, so a small file
+/// declaring a huge canvas (a decompression bomb) is rejected without
+/// allocating it. The file must also end where the image data ends and must
+/// not contain markup, which rejects truncated and polyglot files.
+///
+/// AVIF can't be decoded without the native dav1d library, so its container
+/// structure is checked instead.
+///
+/// This is CPU bound and should be run on a blocking thread.
+pub(crate) fn validate_image(
+    path: &str,
+    format: ImageFormat,
+    limits: &ImageLimits,
+) -> Result<(u32, u32), MyError> {
+    let (width, height) = if format == ImageFormat::Avif {
+        avif_dimensions(path)?
+    } else {
+        let mut reader = Reader::open(path)?;
+        reader.set_format(format);
+        reader.into_dimensions().map_err(|e| {
+            debug!("reading dimensions failed: {}", e);
+            ErrorBadRequest("Invalid image")
+        })?
+    };
+    debug!("image dimensions: {}x{}", width, height);
+
+    if width > limits.max_width || height > limits.max_height {
+        return Err(ErrorBadRequest(format!(
+            "Image dimensions {}x{} exceed the {}x{} limit",
+            width, height, limits.max_width, limits.max_height
+        ))
+        .into());
+    }
+    if width as u64 * height as u64 > limits.max_pixels {
+        return Err(ErrorBadRequest(format!(
+            "Image exceeds the {} pixels limit",
+            limits.max_pixels
+        ))
+        .into());
+    }
+
+    if format != ImageFormat::Avif {
+        let mut decoder_limits = Limits::default();
+        decoder_limits.max_image_width = Some(limits.max_width);
+        decoder_limits.max_image_height = Some(limits.max_height);
+
+        let mut reader = Reader::open(path)?;
+        reader.set_format(format);
+        reader.limits(decoder_limits);
+        reader.decode().map_err(|e| {
+            debug!("decoding failed: {}", e);
+            ErrorBadRequest("Invalid image: cannot be decoded")
+        })?;
+    }
+
+    let mut file = File::open(path)?;
+    check_markup(&mut file)?;
+    check_trailer(&mut file, format)?;
+
+    Ok((width, height))
+}
+
+fn check_markup(file: &mut File) -> Result<(), MyError> {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    file.seek(SeekFrom::Start(0))?;
+    Read::by_ref(file)
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut head)?;
+    let head = head.to_ascii_lowercase();
+
+    if MARKUP_SIGNATURES
+        .iter()
+        .any(|sig| head.windows(sig.len()).any(|w| w == *sig))
+    {
+        return Err(ErrorBadRequest("Invalid image: contains markup").into());
+    }
+    Ok(())
+}
+
+/// Checks that the file ends with the end marker of its format, trailing data is
+/// either a truncated image or something appended to it.
+fn check_trailer(file: &mut File, format: ImageFormat) -> Result<(), MyError> {
+    let len = file.metadata()?.len();
+
+    if format == ImageFormat::WebP {
+        // the RIFF header declares the size of everything that follows it
+        let mut header = [0u8; 8];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        let riff_size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if riff_size as u64 + 8 != len {
+            return Err(ErrorBadRequest("Invalid image: truncated or has trailing data").into());
+        }
+        return Ok(());
+    }
+
+    let mut tail = Vec::with_capacity(64);
+    file.seek(SeekFrom::Start(len.saturating_sub(64)))?;
+    file.read_to_end(&mut tail)?;
+
+    let valid = match format {
+        // encoders may pad the end of a JPEG with zeros
+        ImageFormat::Jpeg => {
+            let end = tail.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+            tail[..end].ends_with(&[0xFF, 0xD9])
+        }
+        ImageFormat::Png => tail.ends_with(&[0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82]),
+        ImageFormat::Gif => tail.ends_with(&[0x3B]),
+        _ => true,
+    };
+
+    if !valid {
+        return Err(ErrorBadRequest("Invalid image: truncated or has trailing data").into());
+    }
+    Ok(())
+}
+
+/// Properties of a stored image reported in the upload response.
+#[derive(Debug, Clone)]
+pub(crate) struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub has_alpha: bool,
+    pub frame_count: u32,
+}
+
+/// Reads the dimensions, transparency and number of frames of the image at `path`
+/// from its headers, without decoding the pixels.
+///
+/// The dimensions are as displayed, i.e. swapped when the EXIF orientation rotates
+/// the image by 90 degrees.
+pub(crate) fn image_info(path: &str, format: ImageFormat) -> Result<ImageInfo, MyError> {
+    let (width, height) = if format == ImageFormat::Avif {
+        avif_dimensions(path)?
+    } else {
+        let mut reader = Reader::open(path)?;
+        reader.set_format(format);
+        let (width, height) = reader.into_dimensions().map_err(img_error)?;
+        if (5..=8).contains(&metadata::read_orientation(path)) {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    };
+
+    let (has_alpha, frame_count) = match format {
+        ImageFormat::Gif => gif_info(&std::fs::read(path)?).ok_or_else(invalid_image)?,
+        ImageFormat::WebP => webp_info(&std::fs::read(path)?).ok_or_else(invalid_image)?,
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(BufReader::new(File::open(path)?)).map_err(img_error)?;
+            let frames = if decoder.is_apng() {
+                apng_frame_count(&std::fs::read(path)?).unwrap_or(1)
+            } else {
+                1
+            };
+            (decoder.color_type().has_alpha(), frames)
+        }
+        ImageFormat::Tiff => {
+            let decoder = TiffDecoder::new(BufReader::new(File::open(path)?)).map_err(img_error)?;
+            (decoder.color_type().has_alpha(), 1)
+        }
+        ImageFormat::Bmp => {
+            let decoder = BmpDecoder::new(BufReader::new(File::open(path)?)).map_err(img_error)?;
+            (decoder.color_type().has_alpha(), 1)
+        }
+        ImageFormat::Ico => {
+            let decoder = IcoDecoder::new(BufReader::new(File::open(path)?)).map_err(img_error)?;
+            (decoder.color_type().has_alpha(), 1)
+        }
+        // the alpha channel of an AVIF is a separate auxiliary image
+        ImageFormat::Avif => {
+            let data = std::fs::read(path)?;
+            let alpha = b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha";
+            (data.windows(alpha.len()).any(|w| w == alpha), 1)
+        }
+        _ => (false, 1),
+    };
+
+    Ok(ImageInfo {
+        width,
+        height,
+        has_alpha,
+        frame_count,
+    })
+}
+
+fn invalid_image() -> actix_web::Error {
+    ErrorBadRequest("Invalid image")
+}
+
+/// Walks the blocks of a GIF, returning whether any frame is transparent and the
+/// number of frames.
+fn gif_info(data: &[u8]) -> Option<(bool, u32)> {
+    // header and logical screen descriptor
+    let flags = *data.get(10)?;
+    let mut i = 13;
+    if flags & 0x80 != 0 {
+        i += 3 << ((flags & 0x07) + 1);
+    }
+
+    let skip_sub_blocks = |mut i: usize| -> Option<usize> {
+        loop {
+            let len = *data.get(i)? as usize;
+            i += 1 + len;
+            if len == 0 {
+                return Some(i);
+            }
+        }
+    };
+
+    let (mut transparent, mut frames) = (false, 0);
+    loop {
+        match *data.get(i)? {
+            // image descriptor, then the LZW minimum code size and the image data
+            0x2C => {
+                frames += 1;
+                let flags = *data.get(i + 9)?;
+                i += 10;
+                if flags & 0x80 != 0 {
+                    i += 3 << ((flags & 0x07) + 1);
+                }
+                i = skip_sub_blocks(i + 1)?;
+            }
+            0x21 => {
+                // graphic control extension with the transparent color flag
+                if *data.get(i + 1)? == 0xF9 && *data.get(i + 3)? & 0x01 != 0 {
+                    transparent = true;
+                }
+                i = skip_sub_blocks(i + 2)?;
+            }
+            0x3B => return Some((transparent, frames)),
+            _ => return None,
+        }
+    }
+}
+
+/// Reads the alpha flag and number of animation frames from the chunks of a WebP.
+fn webp_info(data: &[u8]) -> Option<(bool, u32)> {
+    let (mut alpha, mut frames) = (false, 0);
+    let mut i = 12;
+    while i + 8 <= data.len() {
+        let fourcc = &data[i..i + 4];
+        let len = u32::from_le_bytes([data[i + 4], data[i + 5], data[i + 6], data[i + 7]]) as usize;
+        let content = data.get(i + 8..i + 8 + len)?;
+        match fourcc {
+            b"VP8X" => alpha |= content.first()? & 0x10 != 0,
+            b"ALPH" => alpha = true,
+            // the alpha hint is bit 28 of the lossless header
+            b"VP8L" => alpha |= content.get(4)? & 0x10 != 0,
+            b"ANMF" => frames += 1,
+            _ => {}
+        }
+        i += 8 + len + (len & 1);
+    }
+    Some((alpha, frames.max(1)))
+}
+
+/// Reads the number of frames from the `acTL` chunk of an animated PNG.
+fn apng_frame_count(data: &[u8]) -> Option<u32> {
+    let pos = data.windows(4).position(|w| w == b"acTL")?;
+    let frames = data.get(pos + 4..pos + 8)?;
+    Some(u32::from_be_bytes([
+        frames[0], frames[1], frames[2], frames[3],
+    ]))
+}
+
+/// Conversion applied to images uploaded to an output directory.
+#[derive(Debug, Clone)]
+pub(crate) struct Transcode {
+    /// Format images are converted to, WebP or JPEG.
+    pub target: ImageFormat,
+    /// JPEG encoding quality from 1 to 100, WebP is always encoded lossless.
+    pub quality: u8,
+    /// Formats that are converted, others are stored as is. Defaults to PNG only,
+    /// re-encoding a lossy JPEG as lossless WebP makes it larger.
+    pub from: Vec<ImageFormat>,
+    /// Whether the original upload is stored next to the converted image.
+    pub keep_original: bool,
+}
+
+impl Transcode {
+    /// Reads the conversion of an output directory from `TRANSCODE` (`webp` or
+    /// `jpeg` with an optional quality, e.g. `jpeg:75`), `TRANSCODE_FROM`
+    /// (default `png`) and `TRANSCODE_KEEP_ORIGINAL`.
+    pub(crate) fn from_env(dir_index: Option<&str>) -> Option<Self> {
+        let setting = dir_var("TRANSCODE", dir_index)?;
+        let (target, quality) = match setting.split_once(':') {
+            Some((target, quality)) => (target.trim(), quality.trim().parse().ok()),
+            None => (setting.trim(), None),
+        };
+        let target = match target.to_ascii_lowercase().as_str() {
+            "webp" => ImageFormat::WebP,
+            "jpeg" | "jpg" => ImageFormat::Jpeg,
+            "none" => return None,
+            _ => {
+                warn!("Ignoring unsupported TRANSCODE target: {}", setting);
+                return None;
+            }
+        };
+        let from = dir_var("TRANSCODE_FROM", dir_index)
+            .map(|a| parse_formats(&a))
+            .unwrap_or_else(|| vec![ImageFormat::Png]);
+
+        Some(Self {
+            target,
+            quality: quality.unwrap_or(80u8).clamp(1, 100),
+            from,
+            keep_original: dir_flag("TRANSCODE_KEEP_ORIGINAL", dir_index),
+        })
+    }
+
+    /// Returns whether an image of `format` is converted, an image that is already
+    /// in the target format is not re-encoded.
+    pub(crate) fn applies_to(&self, format: ImageFormat) -> bool {
+        format != self.target && self.from.contains(&format)
+    }
+}
+
+/// Writes `image` to `dst` in the `target` format, lossless WebP or JPEG at
+/// `quality`.
+pub(crate) fn encode_image(
+    image: &DynamicImage,
+    dst: &str,
+    target: ImageFormat,
+    quality: u8,
+) -> Result<(), MyError> {
+    let mut out = BufWriter::new(File::create(dst)?);
+    match target {
+        ImageFormat::WebP => {
+            // the built-in WebP encoder is lossless only, `quality` does not apply
+            let encoder = WebPEncoder::new_lossless(&mut out);
+            if image.color().has_alpha() {
+                let rgba = image.to_rgba8();
+                encoder.encode(&rgba, rgba.width(), rgba.height(), ColorType::Rgba8)
+            } else {
+                let rgb = image.to_rgb8();
+                encoder.encode(&rgb, rgb.width(), rgb.height(), ColorType::Rgb8)
+            }
+            .map_err(img_error)?;
+        }
+        ImageFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let rgb = image.to_rgb8();
+            JpegEncoder::new_with_quality(&mut out, quality)
+                .encode_image(&rgb)
+                .map_err(img_error)?;
+        }
+        _ => return Err(ErrorBadRequest("Unsupported transcode target").into()),
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Decodes the image at `path` with its EXIF orientation applied to the pixels.
+pub(crate) fn open_image(path: &str, format: ImageFormat) -> Result<DynamicImage, MyError> {
+    let mut reader = Reader::open(path)?;
+    reader.set_format(format);
+    Ok(metadata::apply_orientation(
+        reader.decode().map_err(img_error)?,
+        metadata::read_orientation(path),
+    ))
+}
+
+/// Decodes the image at `src` and writes it to `dst` converted as configured.
+///
+/// Returns `false` and writes nothing when the image is animated, as the encoders
+/// only keep the first frame, or when the converted image would be larger than
+/// `src`. This is CPU bound and should be run on a blocking thread.
+pub(crate) fn transcode(
+    src: &str,
+    dst: &str,
+    format: ImageFormat,
+    transcode: &Transcode,
+) -> Result<bool, MyError> {
+    if image_info(src, format)?.frame_count > 1 {
+        debug!("not transcoding animated {:?}", format);
+        return Ok(false);
+    }
+    // the encoders drop the orientation tag, so it is applied to the pixels
+    let image = open_image(src, format)?;
+    debug!(
+        "transcoding {:?} to {:?} at quality {}",
+        format, transcode.target, transcode.quality
+    );
+    encode_image(&image, dst, transcode.target, transcode.quality)?;
+    if std::fs::metadata(dst)?.len() >= std::fs::metadata(src)?.len() {
+        debug!("keeping {:?}, the converted image is not smaller", format);
+        std::fs::remove_file(dst)?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// How a variant is resized into its box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ResizeMode {
+    /// Scale down to fit inside the box, keeping the aspect ratio.
+    Fit,
+    /// Scale and crop to fill the box exactly.
+    Cover,
+}
+
+/// A named size variant generated for every uploaded image, e.g. a thumbnail.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct VariantSpec {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub mode: ResizeMode,
+}
+
+/// A variant written next to the stored image.
+#[derive(Debug)]
+pub(crate) struct Variant {
+    pub name: String,
+    pub file: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Reads the variants of an output directory from `VARIANTS`, e.g.
+/// `VARIANTS=thumb:200x200 cover,medium:1024 fit`.
+pub(crate) fn variants_from_env(dir_index: Option<&str>) -> Vec<VariantSpec> {
+    dir_var("VARIANTS", dir_index)
+        .map(|a| parse_variants(&a))
+        .unwrap_or_default()
+}
+
+/// Parses a comma separated list of `name:WxH mode` variants. A single number is
+/// a square box and the mode defaults to `fit`.
+fn parse_variants(list: &str) -> Vec<VariantSpec> {
+    list.split(',')
+        .filter(|a| !a.trim().is_empty())
+        .filter_map(|spec| {
+            let parsed = parse_variant(spec);
+            if parsed.is_none() {
+                warn!("Ignoring invalid variant in VARIANTS: {}", spec);
+            }
+            parsed
+        })
+        .collect()
+}
+
+fn parse_variant(spec: &str) -> Option<VariantSpec> {
+    let (name, rest) = spec.split_once(':')?;
+    let name = name.trim();
+    // the name ends up in the file name
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return None;
+    }
+
+    let mut parts = rest.split_whitespace();
+    let size = parts.next()?.to_ascii_lowercase();
+    let (width, height) = match size.split_once('x') {
+        Some((w, h)) => (w.parse().ok()?, h.parse().ok()?),
+        None => {
+            let n = size.parse().ok()?;
+            (n, n)
+        }
+    };
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let mode = match parts.next().map(|a| a.to_ascii_lowercase()).as_deref() {
+        None | Some("fit") => ResizeMode::Fit,
+        Some("cover") => ResizeMode::Cover,
+        _ => return None,
+    };
+
+    Some(VariantSpec {
+        name: name.to_owned(),
+        width,
+        height,
+        mode,
+    })
+}
+
+/// Resizes `image` as described by `spec`, images are never upscaled.
+fn resize(image: &DynamicImage, spec: &VariantSpec) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    match spec.mode {
+        ResizeMode::Fit if width <= spec.width && height <= spec.height => image.clone(),
+        ResizeMode::Fit => image.resize(spec.width, spec.height, FilterType::Lanczos3),
+        ResizeMode::Cover => {
+            // crop to the aspect ratio of the box without scaling up
+            let scale = f64::max(
+                spec.width as f64 / width as f64,
+                spec.height as f64 / height as f64,
+            );
+            if scale >= 1.0 {
+                let w = spec.width.min(width);
+                let h = spec.height.min(height);
+                image.crop_imm((width - w) / 2, (height - h) / 2, w, h)
+            } else {
+                image.resize_to_fill(spec.width, spec.height, FilterType::Lanczos3)
+            }
+        }
+    }
+}
+
+/// Generates the variants of the staged image at `path`, decoded as `image`, in
+/// the same `format`. Each is saved next to it as `<path>.<variant>` and is to be
+/// stored as `<hash>_<variant>.<ext>`.
+///
+/// This is CPU bound and should be run on a blocking thread.
+pub(crate) fn generate_variants(
+    image: &DynamicImage,
+    path: &str,
+    hash: &str,
+    format: ImageFormat,
+    specs: &[VariantSpec],
+    quality: u8,
+) -> Result<Vec<Variant>, MyError> {
+    let ext = format_extension(format);
+    specs
+        .iter()
+        .map(|spec| {
+            let resized = resize(image, spec);
+            let file = format!("{}_{}.{}", hash, spec.name, ext);
+            let path = format!("{}.{}", path, spec.name);
+            debug!("variant {}: {}", spec.name, path);
+            match format {
+                ImageFormat::WebP | ImageFormat::Jpeg => {
+                    encode_image(&resized, &path, format, quality)?
+                }
+                _ => resized.save_with_format(&path, format).map_err(img_error)?,
+            }
+            Ok(Variant {
+                name: spec.name.clone(),
+                file,
+                width: resized.width(),
+                height: resized.height(),
+            })
+        })
+        .collect()
+}
+
+/// Iterates over the ISO BMFF boxes in `data`, yielding their type and content.
+fn iter_boxes(data: &[u8]) -> impl Iterator<Item = Result<(&[u8], &[u8]), ()>> {
+    let mut offset = 0usize;
+    std::iter::from_fn(move || {
+        if offset >= data.len() {
+            return None;
+        }
+        let rest = &data[offset..];
+        if rest.len() < 8 {
+            offset = data.len();
+            return Some(Err(()));
+        }
+        let size = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as u64;
+        let (size, header_len) = match size {
+            0 => (rest.len() as u64, 8),
+            1 if rest.len() >= 16 => {
+                let mut large = [0u8; 8];
+                large.copy_from_slice(&rest[8..16]);
+                (u64::from_be_bytes(large), 16)
+            }
+            _ => (size, 8),
+        };
+        if size < header_len as u64 || size > rest.len() as u64 {
+            offset = data.len();
+            return Some(Err(()));
+        }
+        offset += size as usize;
+        Some(Ok((&rest[4..8], &rest[header_len..size as usize])))
+    })
+}
+
+/// Finds the first box of `box_type` in `data`.
+fn find_box<'a>(data: &'a [u8], box_type: &[u8]) -> Option<&'a [u8]> {
+    iter_boxes(data)
+        .filter_map(|b| b.ok())
+        .find(|(t, _)| *t == box_type)
+        .map(|(_, content)| content)
+}
+
+/// Checks the box structure of an AVIF file and reads its dimensions from the
+/// `ispe` property.
+fn avif_dimensions(path: &str) -> Result<(u32, u32), MyError> {
+    let invalid = || ErrorBadRequest("Invalid image");
+    let data = std::fs::read(path)?;
+
+    let mut boxes = Vec::new();
+    for b in iter_boxes(&data) {
+        // a box running past the end of the file means it is truncated
+        boxes.push(b.map_err(|_| invalid())?);
+    }
+
+    match boxes.first() {
+        Some((t, content)) if *t == b"ftyp" && content.len() >= 4 => {
+            if !content
+                .chunks(4)
+                .any(|brand| brand == b"avif" || brand == b"avis")
+            {
+                return Err(invalid().into());
+            }
+        }
+        _ => return Err(invalid().into()),
+    }
+    if !boxes.iter().any(|(t, _)| *t == b"mdat") {
+        return Err(invalid().into());
+    }
+
+    // meta is a full box, its children start after the version and flags
+    let ispe = boxes
+        .iter()
+        .find(|(t, _)| *t == b"meta")
+        .filter(|(_, content)| content.len() > 4)
+        .and_then(|(_, content)| find_box(&content[4..], b"iprp"))
+        .and_then(|iprp| find_box(iprp, b"ipco"))
+        .and_then(|ipco| find_box(ipco, b"ispe"))
+        .filter(|ispe| ispe.len() >= 12)
+        .ok_or_else(invalid)?;
+
+    let width = u32::from_be_bytes([ispe[4], ispe[5], ispe[6], ispe[7]]);
+    let height = u32::from_be_bytes([ispe[8], ispe[9], ispe[10], ispe[11]]);
+
+    Ok((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staging::{test_file, test_path};
+    use image::{ImageOutputFormat, RgbImage};
+    use std::io::Cursor;
+    use std::path::Path;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        RgbImage::new(width, height)
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn test_validate_image() {
+        let limits = ImageLimits {
+            max_width: 100,
+            max_height: 100,
+            max_pixels: 5000,
+        };
+
+        let path = test_file("valid.png", &png(50, 50));
+        assert_eq!(
+            validate_image(&path, ImageFormat::Png, &limits).unwrap(),
+            (50, 50)
+        );
+        assert!(validate_image(&path, ImageFormat::Jpeg, &limits).is_err());
+
+        let path = test_file("wide.png", &png(200, 10));
+        assert!(validate_image(&path, ImageFormat::Png, &limits).is_err());
+
+        let path = test_file("pixels.png", &png(80, 80));
+        assert!(validate_image(&path, ImageFormat::Png, &limits).is_err());
+
+        let data = png(50, 50);
+        let path = test_file("truncated.png", &data[..data.len() - 20]);
+        assert!(validate_image(&path, ImageFormat::Png, &limits).is_err());
+
+        let mut data = png(50, 50);
+        data.extend_from_slice(b"<html><script>alert(1)</script></html>");
+        let path = test_file("polyglot.png", &data);
+        assert!(validate_image(&path, ImageFormat::Png, &limits).is_err());
+
+        for name in [
+            "valid.png",
+            "wide.png",
+            "pixels.png",
+            "truncated.png",
+            "polyglot.png",
+        ] {
+            let _ = std::fs::remove_file(test_path(name));
+        }
+    }
+
+    fn bmff_box(box_type: &[u8], content: &[u8]) -> Vec<u8> {
+        let mut data = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(content);
+        data
+    }
+
+    fn avif(width: u32, height: u32) -> Vec<u8> {
+        let mut ispe = vec![0u8; 4];
+        ispe.extend_from_slice(&width.to_be_bytes());
+        ispe.extend_from_slice(&height.to_be_bytes());
+        let ipco = bmff_box(b"ipco", &bmff_box(b"ispe", &ispe));
+        let mut meta = vec![0u8; 4];
+        meta.extend(bmff_box(b"iprp", &ipco));
+
+        let mut data = bmff_box(b"ftyp", b"avif\0\0\0\0mif1avif");
+        data.extend(bmff_box(b"meta", &meta));
+        data.extend(bmff_box(b"mdat", &[0u8; 16]));
+        data
+    }
+
+    #[test]
+    fn test_image_info() {
+        let path = test_file("info.png", &png(30, 20));
+        let info = image_info(&path, ImageFormat::Png).unwrap();
+        assert_eq!((info.width, info.height), (30, 20));
+        assert!(!info.has_alpha);
+        assert_eq!(info.frame_count, 1);
+        let _ = std::fs::remove_file(&path);
+
+        // two frames, the second with transparent pixels
+        let mut gif = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+            let frames = [image::Rgba([255, 0, 0, 255]), image::Rgba([0, 0, 0, 0])]
+                .iter()
+                .map(|p| image::Frame::new(image::RgbaImage::from_pixel(8, 4, *p)));
+            encoder.encode_frames(frames).unwrap();
+        }
+        let path = test_file("info.gif", &gif);
+        let info = image_info(&path, ImageFormat::Gif).unwrap();
+        assert_eq!((info.width, info.height), (8, 4));
+        assert!(info.has_alpha);
+        assert_eq!(info.frame_count, 2);
+        let _ = std::fs::remove_file(&path);
+
+        let path = test_path("info.webp");
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::new(10, 10));
+        encode_image(&image, &path, ImageFormat::WebP, 80).unwrap();
+        let info = image_info(&path, ImageFormat::WebP).unwrap();
+        assert!(info.has_alpha);
+        assert_eq!(info.frame_count, 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_detect_format() {
+        let all = accepted_formats_from_env(None);
+        assert_eq!(detect_format(&png(1, 1), &all).unwrap(), ImageFormat::Png);
+        assert_eq!(detect_format(&avif(1, 1), &all).unwrap(), ImageFormat::Avif);
+        assert!(detect_format(b"GIF89a", &parse_formats("png, jpg")).is_err());
+        assert!(detect_format(b"hello", &all).is_err());
+
+        assert_eq!(
+            parse_formats("jpg,WEBP,svg"),
+            vec![ImageFormat::Jpeg, ImageFormat::WebP]
+        );
+        assert_eq!(format_extension(ImageFormat::Jpeg), "jpg");
+        assert_eq!(format_name(ImageFormat::Jpeg), "jpeg");
+    }
+
+    #[test]
+    fn test_avif_dimensions() {
+        let path = test_file("valid.avif", &avif(640, 480));
+        assert_eq!(avif_dimensions(&path).unwrap(), (640, 480));
+
+        let data = avif(640, 480);
+        let path = test_file("truncated.avif", &data[..data.len() - 4]);
+        assert!(avif_dimensions(&path).is_err());
+
+        let mut data = avif(640, 480);
+        data.extend_from_slice(b"<html>");
+        let path = test_file("trailing.avif", &data);
+        assert!(avif_dimensions(&path).is_err());
+
+        for name in ["valid.avif", "truncated.avif", "trailing.avif"] {
+            let _ = std::fs::remove_file(test_path(name));
+        }
+    }
+
+    #[test]
+    fn test_transcode() {
+        let config = Transcode {
+            target: ImageFormat::WebP,
+            quality: 80,
+            from: vec![ImageFormat::Png, ImageFormat::Gif],
+            keep_original: false,
+        };
+        assert!(config.applies_to(ImageFormat::Png));
+        assert!(!config.applies_to(ImageFormat::WebP));
+        assert!(!config.applies_to(ImageFormat::Jpeg));
+
+        let src = test_file("transcode.png", &png(32, 16));
+        let dst = &test_path("transcode.webp");
+        assert!(transcode(&src, dst, ImageFormat::Png, &config).unwrap());
+        let limits = ImageLimits::default();
+        assert_eq!(
+            validate_image(dst, ImageFormat::WebP, &limits).unwrap(),
+            (32, 16)
+        );
+        let _ = std::fs::remove_file(dst);
+
+        // only the first frame would be kept
+        let mut gif = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+            let frames = [image::Rgba([255, 0, 0, 255]), image::Rgba([0, 0, 255, 255])]
+                .iter()
+                .map(|p| image::Frame::new(image::RgbaImage::from_pixel(8, 4, *p)));
+            encoder.encode_frames(frames).unwrap();
+        }
+        let animated = test_file("transcode.gif", &gif);
+        assert!(!transcode(&animated, dst, ImageFormat::Gif, &config).unwrap());
+        assert!(!Path::new(dst).exists());
+
+        // a flat image compresses better as PNG than as JPEG
+        let config = Transcode {
+            target: ImageFormat::Jpeg,
+            ..config
+        };
+        assert!(!transcode(&src, dst, ImageFormat::Png, &config).unwrap());
+        assert!(!Path::new(dst).exists());
+
+        let noise = RgbImage::from_fn(64, 64, |x, y| {
+            let v = (x * 7919 + y * 104_729) ^ (x * y * 31);
+            image::Rgb([v as u8, (v >> 8) as u8, (v >> 16) as u8])
+        });
+        let mut data = Vec::new();
+        noise
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .unwrap();
+        let noisy = test_file("transcode-noise.png", &data);
+        assert!(transcode(&noisy, dst, ImageFormat::Png, &config).unwrap());
+        assert_eq!(
+            validate_image(dst, ImageFormat::Jpeg, &limits).unwrap(),
+            (64, 64)
+        );
+
+        for name in [
+            "transcode.png",
+            "transcode.gif",
+            "transcode-noise.png",
+            "transcode.webp",
+        ] {
+            let _ = std::fs::remove_file(test_path(name));
+        }
+    }
+
+    #[test]
+    fn test_parse_variants() {
+        assert_eq!(
+            parse_variants("thumb:200x200 cover, medium:1024 fit,small:64,bad/name:10,x:0"),
+            vec![
+                VariantSpec {
+                    name: "thumb".to_string(),
+                    width: 200,
+                    height: 200,
+                    mode: ResizeMode::Cover,
+                },
+                VariantSpec {
+                    name: "medium".to_string(),
+                    width: 1024,
+                    height: 1024,
+                    mode: ResizeMode::Fit,
+                },
+                VariantSpec {
+                    name: "small".to_string(),
+                    width: 64,
+                    height: 64,
+                    mode: ResizeMode::Fit,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resize() {
+        let image = DynamicImage::ImageRgb8(RgbImage::new(400, 200));
+        let spec = |width, height, mode| VariantSpec {
+            name: "v".to_string(),
+            width,
+            height,
+            mode,
+        };
+        assert_eq!(
+            resize(&image, &spec(100, 100, ResizeMode::Fit)).dimensions(),
+            (100, 50)
+        );
+        assert_eq!(
+            resize(&image, &spec(100, 100, ResizeMode::Cover)).dimensions(),
+            (100, 100)
+        );
+        assert_eq!(
+            resize(&image, &spec(1000, 1000, ResizeMode::Fit)).dimensions(),
+            (400, 200)
+        );
+        assert_eq!(
+            resize(&image, &spec(300, 300, ResizeMode::Cover)).dimensions(),
+            (300, 200)
+        );
+    }
+}
@@ -14,32 +14,59 @@
 /// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 ///
 use actix_cors::Cors;
-use actix_error::ErrorBadRequest;
+use actix_error::{ErrorBadRequest, ErrorNotFound};
 use actix_multipart::Multipart;
 use actix_web::{
-    error as actix_error, middleware, web, App, HttpRequest, HttpResponse, HttpServer,
+    error as actix_error, http::header, middleware, web, App, HttpRequest, HttpResponse, HttpServer,
 };
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
 use dotenvy::dotenv;
 use error::{to_str_err, ApiResult, MyError};
 use futures::{StreamExt, TryStreamExt};
-use image::ImageFormat;
-use log::debug;
+use log::{debug, warn};
+use reqwest::Url;
+use serde::Deserialize;
 use serde_json::json;
 use std::fs::File;
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::{env, io};
 
-use crate::error::img_error;
+use crate::checksum::Verifier;
+use crate::idempotency::{idempotent, Idempotency};
+use crate::progress::{Progress, Tracker};
+use crate::quarantine::{Origin, QuarantineCommand};
+use crate::staging::TmpFile;
+use crate::storage::{Storage, Storages};
+use crate::upload::{OutDir, Stored};
 
 mod error;
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests;
 
+mod checksum;
+mod config;
 mod crypto;
+mod data_uri;
+mod fetch;
+mod idempotency;
+mod imaging;
+mod metadata;
 mod nonce;
+mod pending;
+mod phash;
+mod pipeline;
+mod placeholder;
+mod progress;
+mod quarantine;
+mod s3;
+mod scan;
+mod sniff;
+mod staging;
+mod storage;
+mod svg;
+mod upload;
 
 /// Gets the header value from an [`HttpRequest`](HttpRequest) object.
 ///
@@ -108,20 +135,36 @@ fn verify_signature_nonce_range(secret_key: &str, nonce: &[u64], signature: &str
     result
 }
 
-async fn save_file(req: HttpRequest, mut payload: Multipart) -> ApiResult {
-    let mut save_result: Result<(), io::Error> = Ok(());
-    let max_size = 20 * 1024 * 1024; // 20mb
+/// Key requests are signed with, from `SECRET_KEY`.
+struct SecretKey(String);
 
-    let mut out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
-    let secret_key = env::var("SECRET_KEY").expect("SECRET_KEY not set");
+impl SecretKey {
+    fn from_env() -> Self {
+        Self(env::var("SECRET_KEY").expect("SECRET_KEY not set"))
+    }
+
+    /// The key of the app `req` was sent to.
+    fn of(req: &HttpRequest) -> Result<&str, MyError> {
+        req.app_data::<web::Data<SecretKey>>()
+            .map(|a| a.0.as_str())
+            .ok_or_else(|| anyhow!("SECRET_KEY not configured").into())
+    }
+}
 
-    let signature = get_header_value("X-Signature", &req)?.trim();
+/// Verifies the `X-Signature` header of an upload request against the current
+/// nonce window.
+///
+/// Returns the server side nonce which is echoed back in the response.
+fn verify_request(req: &HttpRequest) -> Result<u64, MyError> {
+    let secret_key = SecretKey::of(req)?;
+
+    let signature = get_header_value("X-Signature", req)?.trim();
     debug!("[client] signature: {}", signature);
-    let nonce_from_client = get_header_value("X-Nonce", &req)?.trim();
+    let nonce_from_client = get_header_value("X-Nonce", req)?.trim();
     let nonce = nonce::nonce();
 
     let calculated_signature =
-        crypto::sign_message(secret_key.as_bytes(), &nonce.to_string().as_bytes());
+        crypto::sign_message(secret_key.as_bytes(), nonce.to_string().as_bytes());
     debug!("[server] signature: {}", calculated_signature);
 
     debug!(
@@ -131,174 +174,579 @@ async fn save_file(req: HttpRequest, mut payload: Multipart) -> ApiResult {
 
     let nonce_range: [u64; 3] = [nonce - 1, nonce, nonce + 1];
 
-    if !verify_signature_nonce_range(&secret_key, &nonce_range, signature) {
+    if !verify_signature_nonce_range(secret_key, &nonce_range, signature) {
         return Err(ErrorBadRequest("Invalid signature.").into());
     }
 
-    let dir_index = if let Ok(dir_index) = get_header_value("X-Dir-Index", &req) {
+    Ok(nonce)
+}
+
+/// Resolves the output directory of a request from its `X-Dir-Index` header,
+/// falling back to `OUT_DIR`.
+fn resolve_out_dir(req: &HttpRequest) -> Result<OutDir, MyError> {
+    let storages = req
+        .app_data::<web::Data<Storages>>()
+        .ok_or_else(|| anyhow!("Storages not configured"))?;
+    if let Ok(dir_index) = get_header_value("X-Dir-Index", req) {
         debug!("client req dir_index: {}", dir_index);
-        out_dir = env::var(format!("OUT_DIR_{}", dir_index))
-            .map_err(|_| ErrorBadRequest(format!("Unknown dir index: {}", dir_index)))?;
-        Some(dir_index)
+        let (path, storage) = storages
+            .get(Some(dir_index))
+            .ok_or_else(|| ErrorBadRequest(format!("Unknown dir index: {}", dir_index)))?;
+        Ok(OutDir::new(path, Some(dir_index.to_owned()), storage))
     } else {
-        None
-    };
+        let (path, storage) = storages.get(None).expect("OUT_DIR not set");
+        Ok(OutDir::new(path, None, storage))
+    }
+}
+
+/// JSON upload body, `data` is either plain base64 or a base64 `data:` URI.
+#[derive(Deserialize)]
+struct JsonUpload {
+    filename: Option<String>,
+    data: String,
+}
+
+async fn save_file(
+    req: HttpRequest,
+    payload: web::Payload,
+    idempotency: web::Data<Idempotency>,
+    progress: web::Data<Progress>,
+) -> ApiResult {
+    let nonce = verify_request(&req)?;
+    let fingerprint = idempotency::fingerprint(&req, &[]);
+    let upload = store_upload(nonce, &req, payload, &progress);
+    idempotent(&idempotency, &req, fingerprint, upload).await
+}
+
+/// Stores the file of a multipart or JSON upload request.
+async fn store_upload(
+    nonce: u64,
+    req: &HttpRequest,
+    payload: web::Payload,
+    progress: &Progress,
+) -> Result<serde_json::Value, MyError> {
+    let out_dir = resolve_out_dir(req)?;
+    let tracker = progress.start(req)?;
+    let stored = tracker.report(receive_upload(&out_dir, req, payload, &tracker).await)?;
+    let secret_key = SecretKey::of(req)?;
+    Ok(upload_response(nonce, secret_key, &out_dir, &stored))
+}
+
+/// Receives the file of a multipart or JSON upload request.
+async fn receive_upload(
+    out_dir: &OutDir,
+    req: &HttpRequest,
+    payload: web::Payload,
+    tracker: &Tracker,
+) -> Result<Stored, MyError> {
+    let is_json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<mime_guess::Mime>().ok())
+        .map(|m| m.essence_str() == "application/json")
+        .unwrap_or(false);
+
+    // reject oversized requests before reading them, the type of the file is not
+    // known yet so only the largest limit can be applied
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(content_length) = content_length {
+        let max_size = out_dir.limits.max();
+        let max_body = if is_json {
+            json_body_limit(max_size)
+        } else {
+            max_size + MULTIPART_OVERHEAD
+        };
+        if content_length > max_body as u64 {
+            return Err(upload::too_large(max_size));
+        }
+    }
+
+    let origin = Origin::from_request(req);
+    if is_json {
+        save_json(out_dir, req, payload, tracker, &origin).await
+    } else {
+        // they describe the whole multipart body, which isn't kept to check them
+        if !checksum::from_headers(req.headers())?.is_empty() {
+            return Err(ErrorBadRequest(
+                "Checksums of a multipart upload must be sent in the headers of the file part",
+            )
+            .into());
+        }
+        let payload = Multipart::new(req.headers(), payload);
+        save_multipart(out_dir, payload, tracker, &origin).await
+    }
+}
 
-    let mut tmp_filepath: Option<String> = None;
-    let mut extension: Option<String> = None;
-    let mut mime_type: Option<mime_guess::Mime> = None;
+/// Builds the JSON body returned for a stored file.
+fn upload_response(
+    nonce: u64,
+    secret_key: &str,
+    out_dir: &OutDir,
+    stored: &Stored,
+) -> serde_json::Value {
+    let mut result = json!({
+        "nonce": nonce,
+        "upload_id": stored.upload_id,
+        "sha1": stored.sha1,
+        "extension": stored.extension,
+        "mime_type": stored.mime_type,
+        "declared_mime_type": stored.declared_mime_type,
+        "detected_mime_type": stored.detected_mime_type,
+        "mime_mismatch": stored.mime_mismatch,
+        "existed": stored.existed,
+        "format": stored.format,
+        "width": stored.image.as_ref().map(|i| i.width),
+        "height": stored.image.as_ref().map(|i| i.height),
+        "has_alpha": stored.image.as_ref().map(|i| i.has_alpha),
+        "frame_count": stored.image.as_ref().map(|i| i.frame_count),
+        "dindex": out_dir.index
+    });
+    if let Some(exif) = &stored.exif {
+        result["exif"] = exif.clone().into();
+    }
+    if !stored.variants.is_empty() {
+        let variants: serde_json::Map<String, serde_json::Value> = stored
+            .variants
+            .iter()
+            .map(|v| {
+                let variant = json!({
+                    "file": v.file,
+                    "width": v.width,
+                    "height": v.height,
+                });
+                (v.name.clone(), variant)
+            })
+            .collect();
+        result["variants"] = variants.into();
+    }
+    if let Some((phash, near_duplicates)) = &stored.phash {
+        result["phash"] = json!(phash);
+        result["near_duplicates"] = json!(near_duplicates);
+    }
+    if let Some(placeholder) = &stored.placeholder {
+        result["placeholder"] = json!(placeholder);
+    }
+    if let Some(pending) = &stored.pending {
+        result["pending"] = json!({
+            "id": pending.id,
+            "token": pending::token(secret_key.as_bytes(), &pending.id),
+            "expires_at": pending.expires_at,
+        });
+    }
+    if let Some(original) = &stored.original {
+        result["original"] = json!({
+            "sha1": original.sha1,
+            "extension": original.extension,
+        });
+    }
+    result
+}
 
-    if let Ok(Some(mut field)) = payload.try_next().await {
+async fn save_multipart(
+    out_dir: &OutDir,
+    mut payload: Multipart,
+    tracker: &Tracker,
+    origin: &Origin,
+) -> Result<Stored, MyError> {
+    if let Ok(Some(field)) = payload.try_next().await {
         debug!("field: {:?}", &field);
         let content = field.content_disposition();
         let filename = content
             .get_filename()
-            .ok_or_else(|| ErrorBadRequest("No filename in content disposition"))?;
+            .ok_or_else(|| ErrorBadRequest("No filename in content disposition"))?
+            .to_owned();
 
         debug!("filename: {}", filename);
 
-        // filename cannot contains /
-        if filename.contains('/') {
-            return Err(ErrorBadRequest("Invalid filename.").into());
-        }
+        let declared = field.content_type().map(|m| m.essence_str().to_owned());
+        let mime_type = upload::resolve_mime_type(&filename, declared.as_deref());
+
+        // checksums of a part describe the file, those of the request the whole body
+        let verifier = Verifier::new(checksum::from_headers(field.headers())?);
+
+        upload::store_stream(
+            out_dir,
+            &filename,
+            mime_type,
+            verifier,
+            tracker,
+            origin,
+            tracker.count(field),
+        )
+        .await
+    } else {
+        Err(ErrorBadRequest("No file uploaded").into())
+    }
+}
 
-        let filename = filename.to_owned();
+/// Room for the multipart boundaries and part headers around the file.
+const MULTIPART_OVERHEAD: usize = 64 * 1024;
 
-        if filename == "blob" {
-            // get from header Content-type
-            let content_type = field.content_type().map(|m| m.essence_str()).unwrap_or_else(|| "image/jpg");
-            debug!("content_type: {}", content_type);
-            debug!("content_type.essence_str(): {}", content_type);
-            mime_type = content_type.parse().ok();
-        }else{
-            mime_type = Some(mime_guess::from_path(&filename).first_or_octet_stream());
+/// Maximum size of a JSON upload body carrying a file of `max_size` bytes.
+fn json_body_limit(max_size: usize) -> usize {
+    // base64 grows the data by a third, leave some room for the rest of the JSON
+    max_size / 3 * 4 + 64 * 1024
+}
+
+async fn save_json(
+    out_dir: &OutDir,
+    req: &HttpRequest,
+    mut payload: web::Payload,
+    tracker: &Tracker,
+    origin: &Origin,
+) -> Result<Stored, MyError> {
+    let limit = json_body_limit(out_dir.limits.max());
+    let mut verifier = Verifier::new(checksum::from_headers(req.headers())?);
+
+    // the encoded body is counted, as `bytes_total` is its length
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(actix_error::Error::from)?;
+        if body.len() + chunk.len() > limit {
+            return Err(upload::too_large(out_dir.limits.max()));
         }
+        tracker.received(chunk.len());
+        verifier.update(&chunk);
+        body.extend_from_slice(&chunk);
+    }
+    verifier.verify()?;
+
+    let upload: JsonUpload = serde_json::from_slice(&body).map_err(|e| anyhow!(e))?;
+    drop(body);
+
+    let (declared, data) = data_uri::parse_data_uri(&upload.data).map_err(ErrorBadRequest)?;
+    let filename = upload.filename.unwrap_or_else(|| "blob".to_string());
+    debug!("filename: {}", filename);
+
+    let mime_type = upload::resolve_mime_type(&filename, declared);
+    let chunks = futures::stream::iter(data_uri::Base64Chunks::new(data));
+
+    upload::store_stream(
+        out_dir,
+        &filename,
+        mime_type,
+        Verifier::none(),
+        tracker,
+        origin,
+        chunks,
+    )
+    .await
+}
 
-        debug!("mime_type: {:?}", mime_type);
+/// Remote file to import with `POST /fetch`.
+#[derive(Deserialize)]
+struct FetchRequest {
+    url: String,
+    filename: Option<String>,
+}
 
-        let a_nonce = nonce::nonce();
-        let tmp_filepath_internal = format!("{}/tmp-{}-{}", out_dir, a_nonce, &filename);
-        debug!("tmp_filepath_internal: {}", tmp_filepath_internal);
+/// Downloads a remote URL on behalf of the client and stores it like an upload.
+async fn fetch_file(
+    req: HttpRequest,
+    body: web::Json<FetchRequest>,
+    idempotency: web::Data<Idempotency>,
+    progress: web::Data<Progress>,
+) -> ApiResult {
+    let nonce = verify_request(&req)?;
+    let body = body.into_inner();
+    let fingerprint = idempotency::fingerprint(
+        &req,
+        &[&body.url, body.filename.as_deref().unwrap_or_default()],
+    );
+    let upload = store_fetched(nonce, &req, body, &progress);
+    idempotent(&idempotency, &req, fingerprint, upload).await
+}
 
-        let mut f = File::create(&tmp_filepath_internal)?;
+/// Downloads the file of a fetch request and stores it.
+async fn store_fetched(
+    nonce: u64,
+    req: &HttpRequest,
+    body: FetchRequest,
+    progress: &Progress,
+) -> Result<serde_json::Value, MyError> {
+    let out_dir = resolve_out_dir(req)?;
+    let tracker = progress.start(req)?;
+    let origin = Origin::from_request(req);
+    let fetched = receive_fetched(&out_dir, body, &tracker, origin).await;
+    let (url, stored) = tracker.report(fetched)?;
+
+    let mut result = upload_response(nonce, SecretKey::of(req)?, &out_dir, &stored);
+    result["url"] = json!(url.as_str());
+
+    Ok(result)
+}
 
-        tmp_filepath = Some(tmp_filepath_internal);
+/// Downloads the file of a fetch request.
+async fn receive_fetched(
+    out_dir: &OutDir,
+    body: FetchRequest,
+    tracker: &Tracker,
+    mut origin: Origin,
+) -> Result<(Url, Stored), MyError> {
+    let config = fetch::FetchConfig::from_env();
+    let (url, response) = fetch::fetch(&body.url, &config).await?;
+    origin.url = Some(url.to_string());
+    // the request is only the URL, the progress is that of the download
+    tracker.total(response.content_length());
+
+    let filename = body
+        .filename
+        .clone()
+        .or_else(|| fetch::filename_from_url(&url))
+        .unwrap_or_else(|| "blob".to_string());
+    debug!("filename: {}", filename);
+
+    let declared = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<mime_guess::Mime>().ok())
+        .map(|m| m.essence_str().to_owned());
+    let mime_type = upload::resolve_mime_type(&filename, declared.as_deref());
+
+    if let Some(content_length) = response.content_length() {
+        upload::check_size(content_length, out_dir.limits.for_mime(mime_type.as_ref()))?;
+    }
 
-        let mut length = 0usize;
+    let stored = upload::store_stream(
+        out_dir,
+        &filename,
+        mime_type,
+        Verifier::none(),
+        tracker,
+        &origin,
+        Box::pin(tracker.count(response.bytes_stream())),
+    )
+    .await?;
+
+    Ok((url, stored))
+}
 
-        // let mut determined_format = false;
+/// Query of `POST /search`.
+#[derive(Deserialize)]
+struct SearchQuery {
+    distance: Option<u32>,
+}
 
-        while let Some(chunk) = field.next().await {
-            let chunk = chunk.unwrap();
-            let file_head = &chunk[0..10];
-            length += chunk.len();
-            if length > max_size {
-                save_result = Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "File size exceeds 20 MB limit",
-                ));
-                break;
-            }
-            f.write_all(&chunk).unwrap();
-            if mime_type.as_ref().map(|a| a.type_().as_str()) == Some("image") {
-                if extension.is_none() {
-                    let format = Some(image::guess_format(file_head).map_err(img_error)?);
-                    match format {
-                        Some(ImageFormat::Png) => {
-                            extension = Some("png".to_string());
-                        }
-                        Some(ImageFormat::Jpeg) => {
-                            extension = Some("jpg".to_string());
-                        }
-                        _ => {
-                            save_result = Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                "Invalid file format. Must be JPEG or PNG.",
-                            ));
-                            break;
-                        }
-                    }
-                }
-            } else {
-                // get extension from the filename
-                let mime_type = mime_type.as_ref().expect("mime_type is None");
-                extension = Path::new(&filename)
-                    .extension()
-                    .and_then(|ext| ext.to_str())
-                    .map(|ext| ext.to_string())
-                    .or_else(|| {
-                        mime_guess::get_mime_extensions(&mime_type)
-                            .and_then(|ext| ext.first().map(|ext| ext.to_string()))
-                    });
-
-                debug!("extension: {:?}", &extension);
-
-                break;
-            }
-        }
+/// Searches the output directory for near-duplicates of the image in the request
+/// body, the image is not stored.
+async fn search_image(
+    req: HttpRequest,
+    query: web::Query<SearchQuery>,
+    payload: web::Payload,
+) -> ApiResult {
+    let nonce = verify_request(&req)?;
+    let out_dir = resolve_out_dir(&req)?;
+
+    let config = out_dir
+        .phash
+        .clone()
+        .ok_or_else(|| ErrorBadRequest("Perceptual hashing is not enabled for this directory"))?;
+    let max_distance = query.distance.unwrap_or(config.max_distance).min(64);
+
+    let (phash, matches) = upload::search_similar(&out_dir, max_distance, payload).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "nonce": nonce,
+        "phash": phash,
+        "matches": matches,
+        "dindex": out_dir.index
+    })))
+}
+
+/// Query of `GET /uploads/{id}` and `GET /uploads/{id}/events`.
+#[derive(Deserialize)]
+struct StatusQuery {
+    signature: String,
+}
+
+/// Verifies the `signature` of a status request, the HMAC of `upload:{id}`
+/// handed out by the application, so only its client can follow an upload.
+fn verify_status_request(req: &HttpRequest, id: &str, query: &StatusQuery) -> Result<(), MyError> {
+    let secret_key = SecretKey::of(req)?;
+    let message = format!("upload:{}", id);
+    if !crypto::verify_signature(secret_key.as_bytes(), message.as_bytes(), &query.signature) {
+        return Err(ErrorBadRequest("Invalid signature.").into());
     }
-    match save_result {
-        Ok(_) => {
-            // calculate hash and rename it accordingly
-            if let Some(tmp_filepath) = tmp_filepath {
-                let hash = move_by_hash(&tmp_filepath)?;
-
-                let mime_type = mime_type.map(|a| a.essence_str().to_owned());
-
-                Ok(HttpResponse::Ok().json(json!({
-                    "nonce": nonce,
-                    "sha1": hash,
-                    "extension": extension.unwrap_or("jpg".to_string()),
-                    "mime_type": mime_type.unwrap_or("application/octet-stream".to_string()),
-                    "dindex": dir_index
-                })))
-            } else {
-                Err(actix_error::ErrorBadRequest("No file uploaded").into())
-            }
-        }
-        Err(e) => Err(actix_web::error::InternalError::new(
-            e,
-            actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
-        ))?,
+    Ok(())
+}
+
+/// Status of an upload, see [`Progress`].
+async fn upload_status(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<StatusQuery>,
+    progress: web::Data<Progress>,
+) -> ApiResult {
+    verify_status_request(&req, &id, &query)?;
+    match progress.status(&id) {
+        Some(status) => Ok(HttpResponse::Ok().json(status)),
+        None => Err(ErrorNotFound("Unknown upload").into()),
     }
 }
 
-/// Moves the given file, renaming it with its SHA1 hash as a file name.
+/// Status of an upload as Server-Sent Events, until it is finished.
+async fn upload_events(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<StatusQuery>,
+    progress: web::Data<Progress>,
+) -> ApiResult {
+    verify_status_request(&req, &id, &query)?;
+    match progress.events(&id) {
+        Some(events) => Ok(HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .streaming(events)),
+        None => Err(ErrorNotFound("Unknown upload").into()),
+    }
+}
+
+/// Pending upload to confirm with `POST /confirm`.
+#[derive(Deserialize)]
+struct ConfirmRequest {
+    id: String,
+    token: String,
+}
+
+/// Moves a pending upload into its output directory, called by the application
+/// once the upload is referenced.
+async fn confirm_upload(
+    req: HttpRequest,
+    body: web::Json<ConfirmRequest>,
+    idempotency: web::Data<Idempotency>,
+) -> ApiResult {
+    let nonce = verify_request(&req)?;
+    let body = body.into_inner();
+    let fingerprint = idempotency::fingerprint(&req, &[&body.id, &body.token]);
+    idempotent(
+        &idempotency,
+        &req,
+        fingerprint,
+        confirm_pending(nonce, &req, body),
+    )
+    .await
+}
+
+/// Confirms the pending upload of a confirm request.
+async fn confirm_pending(
+    nonce: u64,
+    req: &HttpRequest,
+    body: ConfirmRequest,
+) -> Result<serde_json::Value, MyError> {
+    let out_dir = resolve_out_dir(req)?;
+
+    let ConfirmRequest { id, token } = body;
+    let pending_dir = pending::pending_dir(&out_dir.path, out_dir.index.as_deref());
+    let path = out_dir.path.clone();
+    let secret_key = SecretKey::of(req)?.to_owned();
+    let confirmed = web::block(move || {
+        pending::confirm(secret_key.as_bytes(), &pending_dir, &path, &id, &token)
+    })
+    .await
+    .map_err(actix_error::Error::from)??;
+
+    Ok(json!({
+        "nonce": nonce,
+        "sha1": confirmed.sha1,
+        "extension": confirmed.extension,
+        "existed": confirmed.existed,
+        "dindex": out_dir.index
+    }))
+}
+
+/// Moves the given staged file into `storage`, keyed with its SHA1 hash as a file
+/// name. When a file with the same content is already there, it is kept and the
+/// staged file is deleted.
 ///
 /// # Arguments
 ///
-/// * `src_path` - A string slice that holds the path to the file.
+/// * `tmp` - The staged file.
+/// * `storage` - The storage the file is moved to.
+/// * `extension` - The extension of the renamed file.
+/// * `content_type` - The MIME type of the file.
 ///
 /// # Returns
 ///
-/// A `Result` containing a `String` with the file's SHA1 hash.
+/// A `Result` containing the file's SHA1 hash and whether it already existed.
 ///
 /// # Examples
 ///
 /// ```
-/// let hash = move_by_hash("my_image.jpg").unwrap();
+/// let storage = FsStorage::new("/srv/images");
+/// let (hash, existed) = move_by_hash(tmp, &storage, "jpg", "image/jpeg").await.unwrap();
 /// ```
-fn move_by_hash(src_path: &str) -> Result<String, io::Error> {
-    let mut path = PathBuf::from(src_path);
-
-    let extension = Path::new(src_path)
-        .extension()
-        .and_then(|a| a.to_str())
-        .unwrap_or_else(|| "jpg");
-
-    let mut file = File::open(src_path)?;
-    let hash = crypto::get_sha1_file(&mut file)?;
-
-    path.set_file_name(format!("{}.{}", hash, extension));
+async fn move_by_hash(
+    tmp: TmpFile,
+    storage: &dyn Storage,
+    extension: &str,
+    content_type: &str,
+) -> Result<(String, bool), io::Error> {
+    let path = tmp.path().to_owned();
+    let hash = storage::block(move || crypto::get_sha1_file(&mut File::open(path)?)).await?;
+
+    let index = tmp.sibling("index");
+    let indexed = !storage::put_index(storage, index, &hash, extension).await?;
+    let key = format!("{}.{}", hash, extension);
+    match storage.put(tmp, &key, content_type).await {
+        Ok(existed) => Ok((hash, existed)),
+        Err(e) => {
+            if indexed {
+                if let Err(e) = storage.delete(&storage::index_key(&hash)).await {
+                    warn!("Failed to delete the index entry of {}: {}", hash, e);
+                }
+            }
+            Err(e)
+        }
+    }
+}
 
-    let new_path = path.to_str().unwrap().to_string();
+/// Looks up a stored file by its SHA1 hash, `{hash}` or `{hash}.{extension}`, so
+/// clients can skip uploading content that is already there.
+///
+/// Responds `200 OK` with the extension in `X-Extension`, or `404 Not Found`.
+async fn head_object(req: HttpRequest, name: web::Path<String>) -> ApiResult {
+    verify_request(&req)?;
+    let out_dir = resolve_out_dir(&req)?;
+
+    let (hash, extension) = match name.split_once('.') {
+        Some((hash, extension)) => (hash.to_ascii_lowercase(), Some(extension.to_owned())),
+        None => (name.to_ascii_lowercase(), None),
+    };
+    let valid_extension = extension
+        .as_deref()
+        .is_none_or(|a| !a.is_empty() && a.chars().all(|c| c.is_ascii_alphanumeric()));
+    if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) || !valid_extension {
+        return Err(ErrorBadRequest("Invalid object name").into());
+    }
 
-    debug!("old_path: {}", src_path);
-    debug!("new_path: {}", new_path);
+    let found = find_object(&*out_dir.storage, &hash, extension.as_deref()).await?;
 
-    std::fs::rename(src_path, new_path.clone())?;
+    Ok(match found {
+        Some(extension) => HttpResponse::Ok()
+            .content_type(mime_guess::from_ext(&extension).first_or_octet_stream())
+            .insert_header(("X-Extension", extension))
+            .finish(),
+        None => HttpResponse::NotFound().finish(),
+    })
+}
 
-    Ok(hash)
+/// Finds the extension of the file named `hash` in `storage`.
+async fn find_object(
+    storage: &dyn Storage,
+    hash: &str,
+    extension: Option<&str>,
+) -> io::Result<Option<String>> {
+    if let Some(extension) = extension {
+        let exists = storage.exists(&format!("{}.{}", hash, extension)).await?;
+        return Ok(exists.then(|| extension.to_owned()));
+    }
+    storage::find_extension(storage, hash).await
 }
 
 async fn get_nonce() -> ApiResult {
@@ -316,6 +764,18 @@ struct Args {
     /// Sets the host to listen to.
     #[arg(long, default_value = "127.0.0.1")]
     listen: String,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manages the uploads kept in quarantine.
+    Quarantine {
+        #[command(subcommand)]
+        command: QuarantineCommand,
+    },
 }
 
 #[actix_web::main]
@@ -323,13 +783,17 @@ async fn main() -> Result<()> {
     dotenv().expect(".env not found.");
     env_logger::init();
 
+    let args = Args::parse();
+
+    if let Some(Command::Quarantine { command }) = args.command {
+        return quarantine::run_command(command).await;
+    }
+
     println!(
         "\nWelcome to Rantang version {}\n",
         env!("CARGO_PKG_VERSION")
     );
 
-    let args = Args::parse();
-
     let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
 
     // check if exists and create if not
@@ -343,6 +807,7 @@ async fn main() -> Result<()> {
             let dir = value;
             // check if exists and create if not
             if !Path::new(&dir).exists() {
+                #[allow(clippy::expect_fun_call)]
                 std::fs::create_dir_all(&dir).expect(&format!("Failed to create {}", &dir));
             }
             out_dir_count += 1;
@@ -351,6 +816,45 @@ async fn main() -> Result<()> {
     }
     debug!("total out dir: {}", out_dir_count);
 
+    // a misconfigured storage or pipeline fails now rather than on the first upload
+    let secret_key = web::Data::new(SecretKey::from_env());
+    let storages = match Storages::from_env() {
+        Ok(storages) => web::Data::new(storages),
+        Err(e) => panic!("{}", e),
+    };
+    for (dir, index) in config::out_dirs() {
+        if let Err(e) = pipeline::check_env(index.as_deref()) {
+            panic!("Invalid pipeline of {}: {}", dir, e);
+        }
+    }
+
+    // remove tmp files of uploads interrupted by a crash or restart, and keep doing
+    // so for anything that slips through, along with unconfirmed uploads and
+    // expired quarantined ones
+    staging::sweep_all();
+    pending::sweep_all();
+    quarantine::sweep_all();
+    let sweep_interval = staging::sweep_interval();
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(sweep_interval);
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let _ = web::block(|| {
+                staging::sweep_all();
+                pending::sweep_all();
+                quarantine::sweep_all();
+            })
+            .await;
+        }
+    });
+
+    // shared by the workers, so retries are recognized whichever worker gets them
+    let idempotency = web::Data::new(Idempotency::from_env(&secret_key.0));
+    let progress = web::Data::new(Progress::from_env());
+
+    #[allow(clippy::option_as_ref_deref)]
     let cors_allow_all =
         env::var("CORS_ALLOW_ALL").ok().as_ref().map(|a| a.as_str()) == Some("true");
 
@@ -359,7 +863,7 @@ async fn main() -> Result<()> {
 
     if cors_allow_all {
         debug!("CORS_ALLOW_ALL is set to true. Allowing all origins.");
-        HttpServer::new(|| {
+        HttpServer::new(move || {
             let cors = Cors::default()
                 .allow_any_origin()
                 .allow_any_header()
@@ -367,22 +871,42 @@ async fn main() -> Result<()> {
             App::new()
                 .wrap(cors)
                 .wrap(middleware::Logger::default())
+                .app_data(idempotency.clone())
+                .app_data(progress.clone())
+                .app_data(storages.clone())
+                .app_data(secret_key.clone())
                 .route("/get_nonce", web::get().to(get_nonce))
                 // @deprecated: `/image` is deprecated, use `/upload` instead
                 .route("/image", web::post().to(save_file))
                 .route("/upload", web::post().to(save_file))
+                .route("/fetch", web::post().to(fetch_file))
+                .route("/search", web::post().to(search_image))
+                .route("/objects/{name}", web::head().to(head_object))
+                .route("/confirm", web::post().to(confirm_upload))
+                .route("/uploads/{id}", web::get().to(upload_status))
+                .route("/uploads/{id}/events", web::get().to(upload_events))
         })
         .bind(bind)?
         .run()
         .await?;
     } else {
-        HttpServer::new(|| {
+        HttpServer::new(move || {
             App::new()
                 .wrap(middleware::Logger::default())
+                .app_data(idempotency.clone())
+                .app_data(progress.clone())
+                .app_data(storages.clone())
+                .app_data(secret_key.clone())
                 .route("/get_nonce", web::get().to(get_nonce))
                 // @deprecated: `/image` is deprecated, use `/upload` instead
                 .route("/image", web::post().to(save_file))
                 .route("/upload", web::post().to(save_file))
+                .route("/fetch", web::post().to(fetch_file))
+                .route("/search", web::post().to(search_image))
+                .route("/objects/{name}", web::head().to(head_object))
+                .route("/confirm", web::post().to(confirm_upload))
+                .route("/uploads/{id}", web::get().to(upload_status))
+                .route("/uploads/{id}/events", web::get().to(upload_events))
         })
         .bind(bind)?
         .run()
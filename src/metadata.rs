@@ -0,0 +1,719 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use actix_web::error::{ErrorBadRequest, ErrorUnsupportedMediaType};
+use anyhow::anyhow;
+use image::io::Reader;
+use image::{DynamicImage, ImageFormat};
+use log::debug;
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::error::{img_error, MyError};
+use crate::imaging;
+
+/// JPEG markers that carry metadata: APP1 (EXIF, XMP), APP13 (IPTC) and comments.
+const JPEG_METADATA_MARKERS: &[u8] = &[0xE1, 0xED, 0xFE];
+
+/// PNG chunks that carry metadata.
+const PNG_METADATA_CHUNKS: &[&[u8]] = &[b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+/// WebP chunks that carry metadata.
+const WEBP_METADATA_CHUNKS: &[&[u8]] = &[b"EXIF", b"XMP "];
+
+/// Identifiers of GIF application extensions that carry metadata.
+const GIF_METADATA_APPLICATIONS: &[&[u8]] = &[b"XMP DataXMP"];
+
+/// Marks the APP2 segments of a JPEG that hold its ICC profile.
+const JPEG_ICC_ID: &[u8] = b"ICC_PROFILE\0";
+
+/// Reads the EXIF orientation of the image at `path`, `1` (upright) when the
+/// image has none.
+pub(crate) fn read_orientation(path: &str) -> u32 {
+    let exif = match File::open(path).ok().and_then(|f| {
+        exif::Reader::new()
+            .read_from_container(&mut BufReader::new(f))
+            .ok()
+    }) {
+        Some(exif) => exif,
+        None => return 1,
+    };
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .filter(|orientation| (1..=8).contains(orientation))
+        .unwrap_or(1)
+}
+
+/// Maximum length of an EXIF value in the parsed object, longer values are binary
+/// blobs nobody wants to read.
+const MAX_EXIF_VALUE_LEN: usize = 256;
+
+/// Parses the EXIF of the image at `path` into an object of tag names and their
+/// displayed values, e.g. `{"Make": "Canon", "DateTimeOriginal": "2023-05-01 10:12:00"}`.
+///
+/// Only the primary image is read, the embedded thumbnail and maker notes are
+/// skipped. With `redact_gps` the GPS tags are left out.
+pub(crate) fn read_exif(
+    path: &str,
+    redact_gps: bool,
+) -> Option<serde_json::Map<String, serde_json::Value>> {
+    let file = File::open(path).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+
+    let fields: serde_json::Map<_, _> = exif
+        .fields()
+        .filter(|f| f.ifd_num == exif::In::PRIMARY)
+        .filter(|f| f.tag != exif::Tag::MakerNote && f.tag.description().is_some())
+        .filter(|f| !(redact_gps && f.tag.context() == exif::Context::Gps))
+        .filter_map(|f| {
+            let value = f.display_value().with_unit(&exif).to_string();
+            // strings are displayed quoted
+            let value = value
+                .strip_prefix('"')
+                .and_then(|a| a.strip_suffix('"'))
+                .map_or(value.clone(), str::to_owned);
+            if value.len() > MAX_EXIF_VALUE_LEN {
+                return None;
+            }
+            Some((f.tag.to_string(), value.into()))
+        })
+        .collect();
+
+    if fields.is_empty() {
+        None
+    } else {
+        Some(fields)
+    }
+}
+
+/// Rotates and flips the pixels of `image` as described by an EXIF orientation.
+pub(crate) fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Removes EXIF, XMP and IPTC metadata from the image at `path` in place.
+///
+/// When the EXIF orientation is not upright the image is decoded, rotated and
+/// re-encoded so it displays upright without the tag, keeping the ICC color
+/// profile of JPEG, PNG and WebP images. Otherwise metadata is removed without
+/// re-encoding, so no quality is lost and ICC profiles and animation frames are
+/// kept. Animated images are never re-encoded, they lose their orientation along
+/// with the rest of their EXIF.
+///
+/// AVIF metadata can't be removed, AVIF images that carry any are rejected with
+/// `415 Unsupported Media Type`.
+///
+/// This is CPU bound and should be run on a blocking thread.
+pub(crate) fn strip_metadata(path: &str, format: ImageFormat, quality: u8) -> Result<(), MyError> {
+    let orientation = read_orientation(path);
+    debug!("exif orientation: {}", orientation);
+    let data = std::fs::read(path)?;
+    if format == ImageFormat::Avif {
+        if avif_has_metadata(&data) {
+            return Err(
+                ErrorUnsupportedMediaType("Metadata can't be removed from AVIF images").into(),
+            );
+        }
+        return Ok(());
+    }
+
+    // encoders of the image crate don't write any metadata
+    if (orientation != 1 && !is_animated(&data, format)) || format == ImageFormat::Tiff {
+        let mut reader = Reader::open(path)?;
+        reader.set_format(format);
+        let image = apply_orientation(reader.decode().map_err(img_error)?, orientation);
+        let tmp_path = format!("{}.stripped", path);
+        match format {
+            ImageFormat::WebP | ImageFormat::Jpeg => {
+                imaging::encode_image(&image, &tmp_path, format, quality)?
+            }
+            _ => image
+                .save_with_format(&tmp_path, format)
+                .map_err(img_error)?,
+        }
+        if let Some(icc) = icc_profile(&data, format) {
+            let encoded = std::fs::read(&tmp_path)?;
+            let alpha = image.color().has_alpha();
+            let encoded = add_icc_profile(&encoded, format, &icc, alpha)
+                .ok_or_else(|| anyhow!("Failed to add the ICC profile"))?;
+            std::fs::write(&tmp_path, encoded)?;
+        }
+        std::fs::rename(&tmp_path, path)?;
+        return Ok(());
+    }
+
+    let stripped = match format {
+        ImageFormat::Jpeg => strip_jpeg(&data),
+        ImageFormat::Png => strip_png(&data),
+        ImageFormat::WebP => strip_webp(&data),
+        ImageFormat::Gif => strip_gif(&data),
+        _ => return Ok(()),
+    }
+    .ok_or_else(|| ErrorBadRequest("Invalid image"))?;
+
+    if stripped.len() != data.len() {
+        debug!("stripped {} bytes of metadata", data.len() - stripped.len());
+        std::fs::write(path, stripped)?;
+    }
+    Ok(())
+}
+
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..2]);
+    let mut i = 2;
+
+    loop {
+        // markers may be preceded by any number of fill bytes
+        while data.get(i) == Some(&0xFF) && data.get(i + 1) == Some(&0xFF) {
+            i += 1;
+        }
+        if data.get(i) != Some(&0xFF) {
+            return None;
+        }
+        let marker = *data.get(i + 1)?;
+        match marker {
+            // start of scan, the entropy coded data follows until the end
+            0xDA => {
+                out.extend_from_slice(&data[i..]);
+                return Some(out);
+            }
+            0xD9 => {
+                out.extend_from_slice(&data[i..i + 2]);
+                return Some(out);
+            }
+            // standalone markers without a length
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&data[i..i + 2]);
+                i += 2;
+            }
+            _ => {
+                let len = u16::from_be_bytes([*data.get(i + 2)?, *data.get(i + 3)?]) as usize;
+                let end = i + 2 + len;
+                if len < 2 || end > data.len() {
+                    return None;
+                }
+                if !JPEG_METADATA_MARKERS.contains(&marker) {
+                    out.extend_from_slice(&data[i..end]);
+                }
+                i = end;
+            }
+        }
+    }
+}
+
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !data.starts_with(SIGNATURE) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(SIGNATURE);
+    let mut i = SIGNATURE.len();
+
+    while i < data.len() {
+        let len = u32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?) as usize;
+        let chunk_type = data.get(i + 4..i + 8)?;
+        // length, type, data and crc
+        let end = i.checked_add(12)?.checked_add(len)?;
+        if end > data.len() {
+            return None;
+        }
+        if !PNG_METADATA_CHUNKS.contains(&chunk_type) {
+            out.extend_from_slice(&data[i..end]);
+        }
+        i = end;
+    }
+    Some(out)
+}
+
+/// Whether the PNG or WebP image `data` is animated.
+fn is_animated(data: &[u8], format: ImageFormat) -> bool {
+    match format {
+        ImageFormat::Png => png_chunks(data).any(|(chunk_type, _)| chunk_type == b"acTL"),
+        // the animation flag of the VP8X chunk
+        ImageFormat::WebP => {
+            data.get(12..16) == Some(b"VP8X") && data.get(20).is_some_and(|a| a & 0x02 != 0)
+        }
+        _ => false,
+    }
+}
+
+/// The chunk types of a PNG with the chunks including their length and crc, up
+/// to the first malformed one.
+fn png_chunks(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut i = 8;
+    std::iter::from_fn(move || {
+        let len = u32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?) as usize;
+        let end = i.checked_add(12)?.checked_add(len)?;
+        let chunk = data.get(i..end)?;
+        i = end;
+        Some((&chunk[4..8], chunk))
+    })
+}
+
+/// The ICC profile of `data` as the segments or chunks it is stored in, so they can
+/// be copied into a re-encoded image.
+fn icc_profile(data: &[u8], format: ImageFormat) -> Option<Vec<u8>> {
+    let icc: Vec<u8> = match format {
+        ImageFormat::Jpeg => jpeg_segments(data)
+            .filter(|segment| segment[1] == 0xE2 && segment[4..].starts_with(JPEG_ICC_ID))
+            .flatten()
+            .copied()
+            .collect(),
+        ImageFormat::Png => png_chunks(data)
+            .filter(|(chunk_type, _)| *chunk_type == b"iCCP")
+            .flat_map(|(_, chunk)| chunk)
+            .copied()
+            .collect(),
+        ImageFormat::WebP => webp_chunks(data)
+            .filter(|chunk| chunk.starts_with(b"ICCP"))
+            .flatten()
+            .copied()
+            .collect(),
+        _ => Vec::new(),
+    };
+    Some(icc).filter(|a| !a.is_empty())
+}
+
+/// The marker segments of a JPEG before its image data, including their marker.
+fn jpeg_segments(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut i = 2;
+    std::iter::from_fn(move || {
+        let marker = *data.get(i + 1)?;
+        if data[i] != 0xFF || marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let len = u16::from_be_bytes([*data.get(i + 2)?, *data.get(i + 3)?]) as usize;
+        let segment = data.get(i..i + 2 + len)?;
+        i += 2 + len;
+        Some(segment)
+    })
+}
+
+/// The chunks of a WebP, including their header and padding.
+fn webp_chunks(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut i = 12;
+    std::iter::from_fn(move || {
+        let len = u32::from_le_bytes(data.get(i + 4..i + 8)?.try_into().ok()?) as usize;
+        let end = i
+            .checked_add(8)?
+            .checked_add(len + (len & 1))?
+            .min(data.len());
+        let chunk = data.get(i..end)?;
+        i = end;
+        Some(chunk)
+    })
+}
+
+/// Inserts the `icc` profile taken from the original image into the image
+/// `encoded` by the image crate.
+fn add_icc_profile(
+    encoded: &[u8],
+    format: ImageFormat,
+    icc: &[u8],
+    alpha: bool,
+) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(encoded.len() + icc.len() + 18);
+    match format {
+        ImageFormat::Jpeg => {
+            // after the JFIF segment, which must come first
+            let at = match jpeg_segments(encoded).next() {
+                Some(segment) if segment[1] == 0xE0 => 2 + segment.len(),
+                _ => 2,
+            };
+            out.extend_from_slice(encoded.get(..at)?);
+            out.extend_from_slice(icc);
+            out.extend_from_slice(&encoded[at..]);
+        }
+        ImageFormat::Png => {
+            // after the IHDR chunk
+            let (_, ihdr) = png_chunks(encoded).next()?;
+            let at = 8 + ihdr.len();
+            out.extend_from_slice(encoded.get(..at)?);
+            out.extend_from_slice(icc);
+            out.extend_from_slice(&encoded[at..]);
+        }
+        ImageFormat::WebP => {
+            // the extended format announces the profile in a VP8X chunk
+            let (width, height) = webp_dimensions(encoded)?;
+            out.extend_from_slice(encoded.get(..12)?);
+            out.extend_from_slice(b"VP8X");
+            out.extend_from_slice(&10u32.to_le_bytes());
+            out.push(0x20 | if alpha { 0x10 } else { 0 });
+            out.extend_from_slice(&[0, 0, 0]);
+            out.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            out.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+            out.extend_from_slice(icc);
+            out.extend(webp_chunks(encoded).flatten());
+            let riff_size = (out.len() - 8) as u32;
+            out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+        }
+        _ => return None,
+    }
+    Some(out)
+}
+
+/// Dimensions of a simple, lossy or lossless, WebP.
+fn webp_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let chunk = webp_chunks(data).next()?;
+    match chunk.get(..4)? {
+        b"VP8L" => {
+            let bits = u32::from_le_bytes(chunk.get(9..13)?.try_into().ok()?);
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+        }
+        b"VP8 " => {
+            let width = u16::from_le_bytes(chunk.get(14..16)?.try_into().ok()?) & 0x3FFF;
+            let height = u16::from_le_bytes(chunk.get(16..18)?.try_into().ok()?) & 0x3FFF;
+            Some((width as u32, height as u32))
+        }
+        _ => None,
+    }
+}
+
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return None;
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..12]);
+    let mut i = 12;
+
+    while i < data.len() {
+        let fourcc = data.get(i..i + 4)?;
+        let len = u32::from_le_bytes(data.get(i + 4..i + 8)?.try_into().ok()?) as usize;
+        // chunks are padded to an even size
+        let end = i
+            .checked_add(8)?
+            .checked_add(len + (len & 1))?
+            .min(data.len());
+        if i + 8 + len > data.len() {
+            return None;
+        }
+        if !WEBP_METADATA_CHUNKS.contains(&fourcc) {
+            let start = out.len();
+            out.extend_from_slice(&data[i..end]);
+            if fourcc == b"VP8X" && len > 0 {
+                // clear the EXIF and XMP flags
+                out[start + 8] &= !0x0C;
+            }
+        }
+        i = end;
+    }
+
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(out)
+}
+
+fn strip_gif(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 13 || !(data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")) {
+        return None;
+    }
+    // header, logical screen descriptor and global color table
+    let flags = data[10];
+    let mut i = 13;
+    if flags & 0x80 != 0 {
+        i += 3 << ((flags & 0x07) + 1);
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(data.get(..i)?);
+
+    loop {
+        match *data.get(i)? {
+            // trailer
+            0x3B => {
+                out.push(0x3B);
+                return Some(out);
+            }
+            // image descriptor, local color table and image data
+            0x2C => {
+                let flags = *data.get(i + 9)?;
+                let mut end = i + 10;
+                if flags & 0x80 != 0 {
+                    end += 3 << ((flags & 0x07) + 1);
+                }
+                // after the LZW minimum code size
+                let end = gif_sub_blocks_end(data, end + 1)?;
+                out.extend_from_slice(&data[i..end]);
+                i = end;
+            }
+            0x21 => {
+                let label = *data.get(i + 1)?;
+                let end = gif_sub_blocks_end(data, i + 2)?;
+                // comments, and application extensions such as XMP
+                let metadata = label == 0xFE
+                    || (label == 0xFF
+                        && data.get(i + 2) == Some(&11)
+                        && GIF_METADATA_APPLICATIONS.contains(&data.get(i + 3..i + 14)?));
+                if !metadata {
+                    out.extend_from_slice(&data[i..end]);
+                }
+                i = end;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// End of the data sub-blocks of a GIF starting at `i`, after the terminator.
+fn gif_sub_blocks_end(data: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(i)? as usize;
+        i += 1 + len;
+        if len == 0 {
+            return Some(i);
+        }
+    }
+}
+
+/// Whether the AVIF `data` has EXIF or XMP items, they are described in its
+/// `meta` box.
+fn avif_has_metadata(data: &[u8]) -> bool {
+    let mut i = 0;
+    while let Some(header) = data.get(i..i + 8) {
+        let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        let (size, header_len) = match size {
+            0 => ((data.len() - i) as u64, 8),
+            1 => match data.get(i + 8..i + 16) {
+                Some(size) => (u64::from_be_bytes(size.try_into().unwrap()), 16),
+                None => return false,
+            },
+            size => (size, 8),
+        };
+        let end = match usize::try_from(size).ok().and_then(|a| i.checked_add(a)) {
+            Some(end) if size >= header_len && end <= data.len() => end,
+            // a malformed file can't be checked, it is treated as carrying metadata
+            _ => return true,
+        };
+        if &header[4..8] == b"meta" {
+            let meta = &data[i..end];
+            return meta.windows(4).any(|a| a == b"Exif")
+                || meta.windows(19).any(|a| a == b"application/rdf+xml");
+        }
+        i = end;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staging::test_path;
+    use image::{ImageOutputFormat, RgbImage};
+    use std::io::Cursor;
+
+    /// APP1 segment with an EXIF orientation tag.
+    fn exif_app1(orientation: u16) -> Vec<u8> {
+        let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+        tiff.extend_from_slice(&1u16.to_be_bytes());
+        tiff.extend_from_slice(&0x0112u16.to_be_bytes());
+        tiff.extend_from_slice(&3u16.to_be_bytes());
+        tiff.extend_from_slice(&1u32.to_be_bytes());
+        tiff.extend_from_slice(&orientation.to_be_bytes());
+        tiff.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+        let mut payload = b"Exif\0\0".to_vec();
+        payload.extend(tiff);
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend(payload);
+        segment
+    }
+
+    fn jpeg_with_exif(width: u32, height: u32, orientation: u16) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        RgbImage::new(width, height)
+            .write_to(&mut Cursor::new(&mut jpeg), ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        let mut data = jpeg[..2].to_vec();
+        data.extend(exif_app1(orientation));
+        data.extend_from_slice(&jpeg[2..]);
+        data
+    }
+
+    #[test]
+    fn test_strip_jpeg() {
+        let path = test_path("exif.jpg");
+        std::fs::write(&path, jpeg_with_exif(40, 20, 1)).unwrap();
+        assert_eq!(read_orientation(&path), 1);
+
+        strip_metadata(&path, ImageFormat::Jpeg, 90).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        assert!(!data.windows(6).any(|w| w == b"Exif\0\0"));
+        assert_eq!(image::image_dimensions(&path).unwrap(), (40, 20));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_strip_applies_orientation() {
+        let path = test_path("rotated.jpg");
+        std::fs::write(&path, jpeg_with_exif(40, 20, 6)).unwrap();
+        assert_eq!(read_orientation(&path), 6);
+
+        strip_metadata(&path, ImageFormat::Jpeg, 90).unwrap();
+        assert_eq!(read_orientation(&path), 1);
+        assert_eq!(image::image_dimensions(&path).unwrap(), (20, 40));
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_read_exif() {
+        let path = test_path("read.jpg");
+        std::fs::write(&path, jpeg_with_exif(4, 4, 6)).unwrap();
+        let exif = read_exif(&path, true).unwrap();
+        assert_eq!(exif.len(), 1);
+        assert_eq!(exif["Orientation"], "row 0 at right and column 0 at top");
+
+        strip_metadata(&path, ImageFormat::Jpeg, 90).unwrap();
+        assert!(read_exif(&path, false).is_none());
+
+        // IFD0 pointing to a GPS IFD with a GPSVersionID
+        let mut tiff =
+            b"MM\0\x2a\0\0\0\x08\0\x01\x88\x25\0\x04\0\0\0\x01\0\0\0\x1a\0\0\0\0".to_vec();
+        tiff.extend_from_slice(b"\0\x01\0\0\0\x01\0\0\0\x04\x02\x03\0\0\0\0\0\0");
+        let mut payload = b"Exif\0\0".to_vec();
+        payload.extend(tiff);
+        let jpeg = jpeg_with_exif(4, 4, 1);
+        let mut data = jpeg[..2].to_vec();
+        data.extend_from_slice(&[0xFF, 0xE1]);
+        data.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        data.extend(payload);
+        data.extend_from_slice(&jpeg[2..]);
+        std::fs::write(&path, data).unwrap();
+        assert!(read_exif(&path, false)
+            .unwrap()
+            .contains_key("GPSVersionID"));
+        // nothing is left after redacting the GPS tags
+        assert!(read_exif(&path, true).is_none());
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_strip_png() {
+        let mut png = Vec::new();
+        RgbImage::new(4, 4)
+            .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .unwrap();
+        // insert a tEXt chunk after IHDR, the crc is not checked by the stripper
+        let mut data = png[..33].to_vec();
+        data.extend_from_slice(&8u32.to_be_bytes());
+        data.extend_from_slice(b"tEXtGPS\0here");
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(&png[33..]);
+
+        assert_eq!(strip_png(&data).unwrap(), png);
+    }
+
+    #[test]
+    fn test_strip_gif() {
+        let mut gif = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+            let frames = [image::Rgba([255, 0, 0, 255]), image::Rgba([0, 0, 255, 255])]
+                .iter()
+                .map(|p| image::Frame::new(image::RgbaImage::from_pixel(8, 4, *p)));
+            encoder.encode_frames(frames).unwrap();
+        }
+        // a comment and an XMP packet before the trailer
+        let mut data = gif[..gif.len() - 1].to_vec();
+        data.extend_from_slice(b"\x21\xFE\x05GPS!!\x00");
+        data.extend_from_slice(b"\x21\xFF\x0BXMP DataXMP\x04<x/>\x00\x3B");
+
+        let path = test_path("meta.gif");
+        std::fs::write(&path, data).unwrap();
+        strip_metadata(&path, ImageFormat::Gif, 90).unwrap();
+        let stripped = std::fs::read(&path).unwrap();
+        assert_eq!(stripped, gif);
+        // the animation is kept
+        let frames = image::codecs::gif::GifDecoder::new(Cursor::new(stripped)).unwrap();
+        assert_eq!(image::AnimationDecoder::into_frames(frames).count(), 2);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_strip_keeps_icc_profile() {
+        let mut icc = vec![0xFF, 0xE2];
+        let payload = b"ICC_PROFILE\0\x01\x01profile";
+        icc.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        icc.extend_from_slice(payload);
+        let jpeg = jpeg_with_exif(40, 20, 6);
+        let mut data = jpeg[..2].to_vec();
+        data.extend_from_slice(&icc);
+        data.extend_from_slice(&jpeg[2..]);
+
+        // re-encoded to apply the orientation
+        let path = test_path("icc.jpg");
+        std::fs::write(&path, data).unwrap();
+        strip_metadata(&path, ImageFormat::Jpeg, 90).unwrap();
+        let stripped = std::fs::read(&path).unwrap();
+        assert_eq!(image::image_dimensions(&path).unwrap(), (20, 40));
+        assert_eq!(icc_profile(&stripped, ImageFormat::Jpeg), Some(icc));
+        let _ = std::fs::remove_file(path);
+
+        let path = test_path("icc.webp");
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::new(10, 6));
+        imaging::encode_image(&image, &path, ImageFormat::WebP, 80).unwrap();
+        let webp = std::fs::read(&path).unwrap();
+        let icc = b"ICCP\x08\0\0\0profile!".to_vec();
+        let with_icc = add_icc_profile(&webp, ImageFormat::WebP, &icc, true).unwrap();
+        assert_eq!(icc_profile(&with_icc, ImageFormat::WebP), Some(icc));
+        let decoded = image::load_from_memory_with_format(&with_icc, ImageFormat::WebP).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (10, 6));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_avif_metadata() {
+        let boxed = |box_type: &[u8], content: &[u8]| {
+            let mut data = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+            data.extend_from_slice(box_type);
+            data.extend_from_slice(content);
+            data
+        };
+        let mut plain = boxed(b"ftyp", b"avifmif1");
+        plain.extend(boxed(b"meta", b"\0\0\0\0infeav01"));
+        assert!(!avif_has_metadata(&plain));
+        let mut exif = boxed(b"ftyp", b"avifmif1");
+        exif.extend(boxed(b"meta", b"\0\0\0\0infeav01infeExif"));
+        assert!(avif_has_metadata(&exif));
+
+        let path = test_path("meta.avif");
+        std::fs::write(&path, &exif).unwrap();
+        assert!(strip_metadata(&path, ImageFormat::Avif, 90).is_err());
+        std::fs::write(&path, &plain).unwrap();
+        strip_metadata(&path, ImageFormat::Avif, 90).unwrap();
+        let _ = std::fs::remove_file(path);
+    }
+}
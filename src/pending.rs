@@ -0,0 +1,415 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use actix_web::error::ErrorNotFound;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{dir_flag, dir_var, out_dirs};
+use crate::crypto;
+use crate::error::MyError;
+use crate::phash::PhashIndex;
+
+/// Name of the pending directory inside an output directory.
+const PENDING_DIR_NAME: &str = ".pending";
+
+/// Name of the file describing a pending upload inside its directory.
+const MANIFEST: &str = ".manifest.json";
+
+/// Default time a pending upload waits for its confirmation.
+const DEFAULT_TTL: u64 = 24 * 3600;
+
+/// Two-phase upload settings of an output directory.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingConfig {
+    /// Directory pending uploads are kept in until they are confirmed.
+    pub dir: String,
+    pub ttl: Duration,
+}
+
+impl PendingConfig {
+    /// Reads the settings of an output directory from `CONFIRM_UPLOADS`,
+    /// `PENDING_DIR` and `PENDING_TTL` (seconds), `None` when uploads are stored
+    /// right away.
+    pub(crate) fn from_env(out_dir: &str, dir_index: Option<&str>) -> Option<Self> {
+        if !dir_flag("CONFIRM_UPLOADS", dir_index) {
+            return None;
+        }
+        Some(Self {
+            dir: pending_dir(out_dir, dir_index),
+            ttl: ttl(dir_index),
+        })
+    }
+}
+
+/// Returns the pending directory of an output directory, `PENDING_DIR` or the
+/// `.pending` directory inside it. It must be on the same filesystem as the
+/// output directory.
+pub(crate) fn pending_dir(out_dir: &str, dir_index: Option<&str>) -> String {
+    dir_var("PENDING_DIR", dir_index).unwrap_or_else(|| format!("{}/{}", out_dir, PENDING_DIR_NAME))
+}
+
+fn ttl(dir_index: Option<&str>) -> Duration {
+    let ttl = dir_var("PENDING_TTL", dir_index)
+        .and_then(|a| {
+            a.trim().parse().ok().or_else(|| {
+                warn!("Invalid PENDING_TTL: {}", a);
+                None
+            })
+        })
+        .unwrap_or(DEFAULT_TTL);
+    Duration::from_secs(ttl)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |a| a.as_secs())
+}
+
+/// Confirmation token of the pending upload `id`, which only the server can
+/// compute.
+pub(crate) fn token(secret_key: &[u8], id: &str) -> String {
+    crypto::sign_message(secret_key, format!("confirm:{}", id).as_bytes())
+}
+
+/// Describes a pending upload.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    sha1: String,
+    extension: String,
+    /// Unix time after which the upload is deleted.
+    expires_at: u64,
+    /// Perceptual hash added to the index once the upload is confirmed.
+    phash: Option<String>,
+}
+
+/// Directory of an upload waiting for its confirmation. It is deleted when
+/// dropped, unless the upload was stored in it with [`PendingUpload::commit`].
+pub(crate) struct PendingUpload {
+    pub id: String,
+    pub dir: String,
+    pub expires_at: u64,
+    armed: bool,
+}
+
+impl PendingUpload {
+    /// Creates the directory of a new pending upload with a random id.
+    pub(crate) fn create(config: &PendingConfig) -> io::Result<Self> {
+        let mut id = [0u8; 16];
+        getrandom::getrandom(&mut id).map_err(io::Error::other)?;
+        let id = hex::encode(id);
+        let dir = format!("{}/{}", config.dir, id);
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            id,
+            dir,
+            expires_at: now() + config.ttl.as_secs(),
+            armed: true,
+        })
+    }
+
+    /// Records the file stored in the directory, the upload then waits for its
+    /// confirmation until it expires.
+    pub(crate) fn commit(
+        mut self,
+        sha1: &str,
+        extension: &str,
+        phash: Option<&str>,
+    ) -> io::Result<()> {
+        let manifest = Manifest {
+            sha1: sha1.to_owned(),
+            extension: extension.to_owned(),
+            expires_at: self.expires_at,
+            phash: phash.map(str::to_owned),
+        };
+        std::fs::write(
+            format!("{}/{}", self.dir, MANIFEST),
+            serde_json::to_vec(&manifest).map_err(io::Error::other)?,
+        )?;
+        self.armed = false;
+        Ok(())
+    }
+}
+
+impl Drop for PendingUpload {
+    fn drop(&mut self) {
+        if self.armed {
+            if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+                warn!("Failed to remove {}: {}", self.dir, e);
+            }
+        }
+    }
+}
+
+/// A confirmed upload.
+pub(crate) struct Confirmed {
+    pub sha1: String,
+    pub extension: String,
+    /// A file with the same content was already stored.
+    pub existed: bool,
+}
+
+/// Moves the pending upload `id` into `out_dir` when `token` is its
+/// confirmation token.
+///
+/// Unknown, expired and already confirmed uploads are rejected with
+/// `404 Not Found`.
+pub(crate) fn confirm(
+    secret_key: &[u8],
+    pending_dir: &str,
+    out_dir: &str,
+    id: &str,
+    token: &str,
+) -> Result<Confirmed, MyError> {
+    let not_found = || MyError::from(ErrorNotFound("Pending upload not found or expired"));
+    let valid_id = id.len() == 32 && id.chars().all(|c| c.is_ascii_hexdigit());
+    if !valid_id
+        || !crypto::verify_signature(secret_key, format!("confirm:{}", id).as_bytes(), token)
+    {
+        return Err(not_found());
+    }
+
+    // claim the upload, so it is confirmed only once
+    let claimed = format!("{}/{}", pending_dir, id);
+    let dir = format!("{}/.confirming-{}", pending_dir, id);
+    match std::fs::rename(&claimed, &dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(not_found()),
+        Err(e) => return Err(e.into()),
+    }
+    let mut moved = Vec::new();
+    match promote(&dir, out_dir, &mut moved) {
+        Ok(result) => {
+            if let Err(e) = std::fs::remove_dir_all(&dir) {
+                warn!("Failed to remove {}: {}", dir, e);
+            }
+            result.ok_or_else(not_found)
+        }
+        Err(e) => {
+            // put back what was moved and release the claim, so the upload can be
+            // confirmed again
+            for (src, dst) in moved.iter().rev() {
+                if let Err(e) = std::fs::rename(dst, src) {
+                    warn!("Failed to move {} back: {}", dst, e);
+                }
+            }
+            if let Err(e) = std::fs::rename(&dir, &claimed) {
+                warn!("Failed to release {}: {}", dir, e);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Moves the files of a claimed pending upload into `out_dir`, `None` when it
+/// expired. The files moved so far are recorded in `moved` as source and
+/// destination, so they can be put back when it fails.
+fn promote(
+    dir: &str,
+    out_dir: &str,
+    moved: &mut Vec<(PathBuf, String)>,
+) -> Result<Option<Confirmed>, MyError> {
+    let manifest: Manifest =
+        serde_json::from_slice(&std::fs::read(format!("{}/{}", dir, MANIFEST))?)
+            .map_err(io::Error::other)?;
+    if manifest.expires_at <= now() {
+        return Ok(None);
+    }
+
+    let main = format!("{}.{}", manifest.sha1, manifest.extension);
+    let mut existed = false;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name == MANIFEST {
+            continue;
+        }
+        let dst = format!("{}/{}", out_dir, name);
+        if entry.file_type()?.is_dir() {
+            // entries of the hash index
+            std::fs::create_dir_all(&dst)?;
+            for inner in std::fs::read_dir(entry.path())? {
+                let inner = inner?;
+                let dst = format!("{}/{}", dst, inner.file_name().to_string_lossy());
+                if !Path::new(&dst).is_file() {
+                    std::fs::rename(inner.path(), &dst)?;
+                    moved.push((inner.path(), dst));
+                }
+            }
+            continue;
+        }
+        // identical content is already there
+        if Path::new(&dst).is_file() {
+            existed |= name == main;
+            continue;
+        }
+        debug!("confirming {}", dst);
+        std::fs::rename(entry.path(), &dst)?;
+        moved.push((entry.path(), dst));
+    }
+
+    if let Some(phash) = manifest
+        .phash
+        .and_then(|a| u64::from_str_radix(&a, 16).ok())
+    {
+        PhashIndex::open(out_dir).insert(phash, &manifest.sha1)?;
+    }
+    Ok(Some(Confirmed {
+        sha1: manifest.sha1,
+        extension: manifest.extension,
+        existed,
+    }))
+}
+
+/// Deletes the pending uploads in `dir` that expired, or were left incomplete
+/// longer than `ttl`, returns how many were deleted.
+pub(crate) fn sweep_dir(dir: &str, ttl: Duration) -> io::Result<usize> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let now = now();
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if !metadata.is_dir() {
+            continue;
+        }
+        let manifest = std::fs::read(entry.path().join(MANIFEST))
+            .ok()
+            .and_then(|a| serde_json::from_slice::<Manifest>(&a).ok());
+        let expires_at = match manifest {
+            Some(manifest) => manifest.expires_at,
+            None => metadata
+                .modified()
+                .ok()
+                .and_then(|a| a.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |a| a.as_secs())
+                .saturating_add(ttl.as_secs()),
+        };
+        if expires_at <= now {
+            debug!("removing expired pending upload: {:?}", entry.path());
+            std::fs::remove_dir_all(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Deletes expired pending uploads of every output directory.
+pub(crate) fn sweep_all() {
+    for (out_dir, index) in out_dirs() {
+        let dir = pending_dir(&out_dir, index.as_deref());
+        match sweep_dir(&dir, ttl(index.as_deref())) {
+            Ok(0) => {}
+            Ok(n) => info!("removed {} expired pending uploads from {}", n, dir),
+            Err(e) => warn!("Failed to sweep {}: {}", dir, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staging::test_path;
+
+    const KEY: &[u8] = b"test";
+
+    fn expired(config: &PendingConfig) -> PendingConfig {
+        PendingConfig {
+            dir: config.dir.clone(),
+            ttl: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn test_confirm() {
+        let root = &test_path("pending");
+        let _ = std::fs::remove_dir_all(root);
+        let config = PendingConfig {
+            dir: format!("{}/.pending", root),
+            ttl: Duration::from_secs(60),
+        };
+
+        // abandoned while it was written
+        let upload = PendingUpload::create(&config).unwrap();
+        let dir = upload.dir.clone();
+        drop(upload);
+        assert!(!Path::new(&dir).exists());
+
+        let upload = PendingUpload::create(&config).unwrap();
+        let id = upload.id.clone();
+        std::fs::write(format!("{}/abc.txt", upload.dir), "abc").unwrap();
+        std::fs::write(format!("{}/abc_thumb.txt", upload.dir), "a").unwrap();
+        upload
+            .commit("abc", "txt", Some("00000000000000ff"))
+            .unwrap();
+
+        assert!(confirm(KEY, &config.dir, root, &id, &token(KEY, "other")).is_err());
+        assert!(confirm(b"other", &config.dir, root, &id, &token(KEY, &id)).is_err());
+        let confirmed = confirm(KEY, &config.dir, root, &id, &token(KEY, &id)).unwrap();
+        assert_eq!(confirmed.sha1, "abc");
+        assert!(!confirmed.existed);
+        assert_eq!(
+            std::fs::read_to_string(format!("{}/abc.txt", root)).unwrap(),
+            "abc"
+        );
+        assert!(Path::new(&format!("{}/abc_thumb.txt", root)).is_file());
+        assert_eq!(
+            std::fs::read_to_string(format!("{}/.phash-index", root)).unwrap(),
+            "00000000000000ff abc\n"
+        );
+        // only once
+        assert!(confirm(KEY, &config.dir, root, &id, &token(KEY, &id)).is_err());
+
+        // failed halfway, the files are put back and it can be confirmed again
+        let upload = PendingUpload::create(&config).unwrap();
+        let id = upload.id.clone();
+        std::fs::write(format!("{}/def.txt", upload.dir), "def").unwrap();
+        upload
+            .commit("def", "txt", Some("00000000000000fe"))
+            .unwrap();
+        std::fs::remove_file(format!("{}/.phash-index", root)).unwrap();
+        std::fs::create_dir(format!("{}/.phash-index", root)).unwrap();
+        assert!(confirm(KEY, &config.dir, root, &id, &token(KEY, &id)).is_err());
+        assert!(!Path::new(&format!("{}/def.txt", root)).exists());
+        assert!(Path::new(&format!("{}/{}/def.txt", config.dir, id)).is_file());
+        std::fs::remove_dir(format!("{}/.phash-index", root)).unwrap();
+        assert!(confirm(KEY, &config.dir, root, &id, &token(KEY, &id)).is_ok());
+        assert!(Path::new(&format!("{}/def.txt", root)).is_file());
+
+        // expired
+        let upload = PendingUpload::create(&expired(&config)).unwrap();
+        let id = upload.id.clone();
+        upload.commit("abc", "txt", None).unwrap();
+        assert!(confirm(KEY, &config.dir, root, &id, &token(KEY, &id)).is_err());
+
+        let upload = PendingUpload::create(&expired(&config)).unwrap();
+        upload.commit("abc", "txt", None).unwrap();
+        let upload = PendingUpload::create(&config).unwrap();
+        upload.commit("abc", "txt", None).unwrap();
+        assert_eq!(sweep_dir(&config.dir, config.ttl).unwrap(), 1);
+        assert_eq!(std::fs::read_dir(&config.dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
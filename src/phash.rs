@@ -0,0 +1,209 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use image::imageops::FilterType;
+use image::DynamicImage;
+use log::{debug, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+
+use crate::config::{dir_flag, dir_var};
+
+/// Name of the index file kept in every output directory with perceptual hashing.
+const INDEX_FILE: &str = ".phash-index";
+
+/// Default maximum Hamming distance between the hashes of near-duplicates.
+const DEFAULT_MAX_DISTANCE: u32 = 10;
+
+/// Perceptual hashing settings of an output directory.
+#[derive(Debug, Clone)]
+pub(crate) struct PhashConfig {
+    /// Maximum Hamming distance between the hashes of near-duplicates.
+    pub max_distance: u32,
+}
+
+impl PhashConfig {
+    /// Reads the settings of an output directory from `PHASH` and
+    /// `PHASH_DISTANCE`, `None` when perceptual hashing is disabled.
+    pub(crate) fn from_env(dir_index: Option<&str>) -> Option<Self> {
+        if !dir_flag("PHASH", dir_index) {
+            return None;
+        }
+        let max_distance = dir_var("PHASH_DISTANCE", dir_index)
+            .and_then(|a| {
+                a.trim().parse().ok().filter(|d| *d <= 64).or_else(|| {
+                    warn!("Invalid PHASH_DISTANCE: {}", a);
+                    None
+                })
+            })
+            .unwrap_or(DEFAULT_MAX_DISTANCE);
+        Some(Self { max_distance })
+    }
+}
+
+/// Computes the 64 bit difference hash (dHash) of `image`.
+///
+/// The image is reduced to 9x8 grayscale pixels and every bit tells whether a
+/// pixel is brighter than its right neighbour, so re-encoding, resizing and small
+/// color changes barely change the hash.
+pub(crate) fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).into_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let bit = small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0];
+            hash = (hash << 1) | bit as u64;
+        }
+    }
+    hash
+}
+
+/// Formats a hash as 16 hex digits.
+pub(crate) fn to_hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+/// An image in the index whose hash is close to the searched one.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub(crate) struct Match {
+    pub sha1: String,
+    pub phash: String,
+    pub distance: u32,
+}
+
+/// Index of the perceptual hashes of the images in an output directory, one
+/// `<phash> <sha1>` line per image.
+pub(crate) struct PhashIndex {
+    path: String,
+}
+
+impl PhashIndex {
+    pub(crate) fn open(dir: &str) -> Self {
+        Self {
+            path: format!("{}/{}", dir, INDEX_FILE),
+        }
+    }
+
+    fn entries(&self) -> io::Result<Vec<(u64, String)>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let parsed = line.split_once(' ').and_then(|(phash, sha1)| {
+                u64::from_str_radix(phash, 16)
+                    .ok()
+                    .map(|phash| (phash, sha1.trim().to_owned()))
+            });
+            match parsed {
+                Some(entry) => entries.push(entry),
+                None => warn!("Invalid line in {}: {}", self.path, line),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Finds the images whose hash is within `max_distance` of `hash`, closest
+    /// first. The image `exclude` is left out.
+    pub(crate) fn search(
+        &self,
+        hash: u64,
+        max_distance: u32,
+        exclude: Option<&str>,
+    ) -> io::Result<Vec<Match>> {
+        let mut matches: Vec<Match> = self
+            .entries()?
+            .into_iter()
+            .filter(|(_, sha1)| Some(sha1.as_str()) != exclude)
+            .map(|(phash, sha1)| Match {
+                sha1,
+                phash: to_hex(phash),
+                distance: (phash ^ hash).count_ones(),
+            })
+            .filter(|m| m.distance <= max_distance)
+            .collect();
+        matches.sort_by(|a, b| a.distance.cmp(&b.distance).then(a.sha1.cmp(&b.sha1)));
+        matches.dedup_by(|a, b| a.sha1 == b.sha1);
+        Ok(matches)
+    }
+
+    /// Adds an image to the index unless it is already in it.
+    pub(crate) fn insert(&self, hash: u64, sha1: &str) -> io::Result<()> {
+        if self.entries()?.iter().any(|(_, s)| s == sha1) {
+            return Ok(());
+        }
+        debug!("indexing {} as {}", sha1, to_hex(hash));
+        // a single short write to a file opened for appending is atomic, so
+        // concurrent uploads don't interleave their lines
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(format!("{} {}\n", to_hex(hash), sha1).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staging::test_path;
+    use image::{Rgb, RgbImage};
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let v = ((x * 7 + y * 3) % 256) as u8;
+            Rgb([v, 255 - v, (x * y % 256) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_dhash() {
+        let image = gradient(200, 150);
+        let hash = dhash(&image);
+        // a resized copy is a near-duplicate
+        let resized = image.resize(100, 75, FilterType::Lanczos3);
+        assert!((dhash(&resized) ^ hash).count_ones() <= 4);
+        // a mirrored copy is not
+        assert!((dhash(&image.fliph()) ^ hash).count_ones() > 10);
+    }
+
+    #[test]
+    fn test_index() {
+        let dir = test_path("phash");
+        std::fs::create_dir_all(&dir).unwrap();
+        let index = PhashIndex::open(&dir);
+
+        index.insert(0xff00, "a").unwrap();
+        index.insert(0xff01, "b").unwrap();
+        index.insert(0x00ff, "c").unwrap();
+        index.insert(0xff01, "b").unwrap();
+
+        let matches = index.search(0xff00, 2, Some("a")).unwrap();
+        assert_eq!(
+            matches,
+            vec![Match {
+                sha1: "b".to_string(),
+                phash: "000000000000ff01".to_string(),
+                distance: 1
+            }]
+        );
+        assert_eq!(index.search(0xff00, 16, None).unwrap().len(), 3);
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
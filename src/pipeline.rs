@@ -0,0 +1,551 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+// Checks and conversions applied to an upload after it is staged and before it is
+// moved into its output directory.
+//
+// Each step is a [`Validator`], which accepts or rejects the upload, or a
+// [`Processor`], which may change the staged file and what is known about it.
+// The chain of an output directory is configured with `PIPELINE`, custom steps
+// implement one of the traits and are added to [`STEPS`] and [`build_step`].
+use actix_web::error::{
+    ErrorServiceUnavailable, ErrorUnprocessableEntity, ErrorUnsupportedMediaType,
+};
+use actix_web::web;
+use futures::future::LocalBoxFuture;
+use image::ImageFormat;
+use log::{debug, error, warn};
+use std::sync::Arc;
+
+use crate::config::{dir_var, SizeLimits};
+use crate::error::MyError;
+use crate::imaging::{self, ImageLimits, Transcode};
+use crate::progress::{State, Tracker};
+use crate::scan::{self, ScanConfig, ScanResult};
+use crate::sniff::{MimeFilter, MismatchPolicy};
+use crate::staging::TmpFile;
+use crate::storage::Storage;
+use crate::upload::{check_size, Original, OutDir, SVG_MIME_TYPE};
+use crate::{metadata, move_by_hash, svg};
+
+/// Names of the known steps, in the order of a chain when `PIPELINE` is not set.
+/// Steps that aren't enabled in the output directory are left out.
+const STEPS: &[&str] = &[
+    "size",
+    "mime",
+    "scan",
+    "svg",
+    "image",
+    "strip_metadata",
+    "transcode",
+];
+
+/// Steps every chain must have, without them SVGs would be stored unsanitized and
+/// images undecoded.
+const REQUIRED_STEPS: &[&str] = &["size", "mime", "svg", "image"];
+
+/// A staged upload and what is known about it.
+pub(crate) struct Upload {
+    pub tmp: TmpFile,
+    /// Storage the upload is stored in.
+    pub storage: Arc<dyn Storage>,
+    /// Size of the upload as it was received.
+    pub length: u64,
+    /// Type the client uploaded the file as.
+    pub declared_mime_type: String,
+    /// Type detected from the content of the file.
+    pub detected_mime_type: &'static str,
+    /// The detected type doesn't match the declared one.
+    pub mime_mismatch: bool,
+    /// Type the file is stored as.
+    pub mime_type: String,
+    /// Detected format of images.
+    pub format: Option<ImageFormat>,
+    pub extension: String,
+    /// EXIF of the upload as it was received.
+    pub exif: Option<serde_json::Map<String, serde_json::Value>>,
+    /// The upload as it was received, when it was converted and kept.
+    pub original: Option<Original>,
+    /// Signature of the malware found in the upload.
+    pub infected: Option<String>,
+}
+
+/// A step that accepts or rejects an upload without changing it.
+pub(crate) trait Validator {
+    fn name(&self) -> &'static str;
+
+    fn validate<'a>(&'a self, upload: &'a Upload) -> LocalBoxFuture<'a, Result<(), MyError>>;
+}
+
+/// A step that may change an upload.
+pub(crate) trait Processor {
+    fn name(&self) -> &'static str;
+
+    fn process<'a>(&'a self, upload: &'a mut Upload) -> LocalBoxFuture<'a, Result<(), MyError>>;
+}
+
+/// A step of a chain.
+pub(crate) enum Step {
+    Validate(Box<dyn Validator>),
+    Process(Box<dyn Processor>),
+}
+
+impl Step {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Step::Validate(validator) => validator.name(),
+            Step::Process(processor) => processor.name(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Reads the step names of an output directory from `PIPELINE`, a comma separated
+/// list, e.g. `PIPELINE_2=size,mime,svg,image,transcode`.
+fn step_names(dir_index: Option<&str>) -> Vec<String> {
+    match dir_var("PIPELINE", dir_index) {
+        Some(names) => names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect(),
+        None => STEPS.iter().map(|name| name.to_string()).collect(),
+    }
+}
+
+/// Checks that `PIPELINE` of an output directory only names known steps, so a
+/// typo can't silently disable a check, and has all the required ones.
+pub(crate) fn check_env(dir_index: Option<&str>) -> Result<(), String> {
+    check_steps(&step_names(dir_index))
+}
+
+fn check_steps(names: &[String]) -> Result<(), String> {
+    if let Some(name) = names.iter().find(|name| !STEPS.contains(&name.as_str())) {
+        return Err(format!("unknown pipeline step: {}", name));
+    }
+    match REQUIRED_STEPS
+        .iter()
+        .find(|required| !names.iter().any(|name| name == *required))
+    {
+        Some(name) => Err(format!("missing required pipeline step: {}", name)),
+        None => Ok(()),
+    }
+}
+
+/// Builds the chain of an output directory from `PIPELINE`.
+pub(crate) fn from_env(out_dir: &OutDir) -> Vec<Step> {
+    step_names(out_dir.index.as_deref())
+        .iter()
+        .filter_map(|name| build_step(name, out_dir))
+        .collect()
+}
+
+/// Creates the step called `name` with the settings of `out_dir`, `None` when it
+/// is not enabled there. Unknown names are refused at startup by [`check_env`].
+pub(crate) fn build_step(name: &str, out_dir: &OutDir) -> Option<Step> {
+    match name {
+        "size" => Some(Step::Validate(Box::new(SizeCheck {
+            limits: out_dir.limits.clone(),
+        }))),
+        "mime" => Some(Step::Validate(Box::new(MimeCheck {
+            policy: out_dir.mime_mismatch,
+            filter: out_dir.mime_filter.clone(),
+        }))),
+        "scan" => out_dir
+            .scan
+            .clone()
+            .map(|config| Step::Process(Box::new(Scan { config }))),
+        "svg" => Some(Step::Process(Box::new(SanitizeSvg))),
+        "image" => Some(Step::Process(Box::new(DecodeImage {
+            limits: out_dir.image_limits.clone(),
+            redact_gps: out_dir.redact_gps,
+        }))),
+        "strip_metadata" => out_dir.strip_metadata.then(|| {
+            Step::Process(Box::new(StripMetadata {
+                quality: out_dir.transcode.as_ref().map_or(90, |t| t.quality),
+            }))
+        }),
+        "transcode" => out_dir
+            .transcode
+            .clone()
+            .map(|config| Step::Process(Box::new(TranscodeImage { config }))),
+        _ => None,
+    }
+}
+
+/// Passes `upload` through `steps` in order, stopping at the first error.
+pub(crate) async fn run(
+    steps: &[Step],
+    upload: &mut Upload,
+    tracker: &Tracker,
+) -> Result<(), MyError> {
+    for step in steps {
+        debug!("pipeline step: {}", step.name());
+        match step {
+            Step::Validate(validator) => {
+                tracker.step(State::Validating, validator.name());
+                validator.validate(upload).await?;
+            }
+            Step::Process(processor) => {
+                tracker.step(State::Processing, processor.name());
+                processor.process(upload).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies the size limit of the type an upload is stored as, which can differ
+/// from the declared type that was checked while it was received.
+pub(crate) struct SizeCheck {
+    pub limits: SizeLimits,
+}
+
+impl Validator for SizeCheck {
+    fn name(&self) -> &'static str {
+        "size"
+    }
+
+    fn validate<'a>(&'a self, upload: &'a Upload) -> LocalBoxFuture<'a, Result<(), MyError>> {
+        Box::pin(async move {
+            let mime_type = upload.mime_type.parse().ok();
+            check_size(upload.length, self.limits.for_mime(mime_type.as_ref()))
+        })
+    }
+}
+
+/// Rejects uploads whose content doesn't match their declared type, or whose type
+/// the directory doesn't accept.
+pub(crate) struct MimeCheck {
+    pub policy: MismatchPolicy,
+    pub filter: MimeFilter,
+}
+
+impl Validator for MimeCheck {
+    fn name(&self) -> &'static str {
+        "mime"
+    }
+
+    fn validate<'a>(&'a self, upload: &'a Upload) -> LocalBoxFuture<'a, Result<(), MyError>> {
+        Box::pin(async move {
+            if upload.mime_mismatch && self.policy == MismatchPolicy::Reject {
+                return Err(ErrorUnsupportedMediaType(format!(
+                    "File content is {} but it was uploaded as {}",
+                    upload.detected_mime_type, upload.declared_mime_type
+                ))
+                .into());
+            }
+            self.filter.check(&upload.mime_type)?;
+            self.filter.check_denied(upload.detected_mime_type)
+        })
+    }
+}
+
+/// Scans uploads with clamd, infected files are flagged so they are quarantined.
+pub(crate) struct Scan {
+    pub config: ScanConfig,
+}
+
+impl Processor for Scan {
+    fn name(&self) -> &'static str {
+        "scan"
+    }
+
+    fn process<'a>(&'a self, upload: &'a mut Upload) -> LocalBoxFuture<'a, Result<(), MyError>> {
+        Box::pin(async move {
+            match scan::scan_file(upload.tmp.path(), &self.config).await {
+                Ok(ScanResult::Clean) => Ok(()),
+                Ok(ScanResult::Infected(signature)) => {
+                    let error =
+                        ErrorUnprocessableEntity(format!("File is infected: {}", signature));
+                    upload.infected = Some(signature);
+                    Err(error.into())
+                }
+                Err(e) if self.config.fail_open => {
+                    warn!("malware scan failed, accepting file: {}", e);
+                    Ok(())
+                }
+                Err(e) => {
+                    error!("malware scan failed: {}", e);
+                    Err(ErrorServiceUnavailable("Malware scan unavailable").into())
+                }
+            }
+        })
+    }
+}
+
+/// Removes anything that can run or load content from SVGs, which are served as
+/// documents.
+pub(crate) struct SanitizeSvg;
+
+impl Processor for SanitizeSvg {
+    fn name(&self) -> &'static str {
+        "svg"
+    }
+
+    fn process<'a>(&'a self, upload: &'a mut Upload) -> LocalBoxFuture<'a, Result<(), MyError>> {
+        Box::pin(async move {
+            if upload.detected_mime_type == SVG_MIME_TYPE {
+                let path = upload.tmp.path().to_owned();
+                web::block(move || svg::sanitize_file(&path))
+                    .await
+                    .map_err(actix_web::Error::from)??;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Decodes images to check they are valid and within `limits`, and reads their
+/// EXIF.
+pub(crate) struct DecodeImage {
+    pub limits: ImageLimits,
+    pub redact_gps: bool,
+}
+
+impl Processor for DecodeImage {
+    fn name(&self) -> &'static str {
+        "image"
+    }
+
+    fn process<'a>(&'a self, upload: &'a mut Upload) -> LocalBoxFuture<'a, Result<(), MyError>> {
+        Box::pin(async move {
+            if let Some(format) = upload.format {
+                let path = upload.tmp.path().to_owned();
+                let (limits, redact_gps) = (self.limits.clone(), self.redact_gps);
+                upload.exif = web::block(move || {
+                    imaging::validate_image(&path, format, &limits)
+                        .map(|_| metadata::read_exif(&path, redact_gps))
+                })
+                .await
+                .map_err(actix_web::Error::from)??;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Removes location and camera details from images.
+pub(crate) struct StripMetadata {
+    /// Quality of re-encoded JPEGs.
+    pub quality: u8,
+}
+
+impl Processor for StripMetadata {
+    fn name(&self) -> &'static str {
+        "strip_metadata"
+    }
+
+    fn process<'a>(&'a self, upload: &'a mut Upload) -> LocalBoxFuture<'a, Result<(), MyError>> {
+        Box::pin(async move {
+            if let Some(format) = upload.format {
+                let (path, quality) = (upload.tmp.path().to_owned(), self.quality);
+                web::block(move || metadata::strip_metadata(&path, format, quality))
+                    .await
+                    .map_err(actix_web::Error::from)??;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Converts images, the original is stored next to the upload when it is kept.
+pub(crate) struct TranscodeImage {
+    pub config: Transcode,
+}
+
+impl Processor for TranscodeImage {
+    fn name(&self) -> &'static str {
+        "transcode"
+    }
+
+    fn process<'a>(&'a self, upload: &'a mut Upload) -> LocalBoxFuture<'a, Result<(), MyError>> {
+        Box::pin(async move {
+            let src_format = match upload.format {
+                Some(format) if self.config.applies_to(format) => format,
+                _ => return Ok(()),
+            };
+            let target = self.config.target;
+            let converted = upload.tmp.sibling(imaging::format_extension(target));
+            let (src, dst, config) = (
+                upload.tmp.path().to_owned(),
+                converted.path().to_owned(),
+                self.config.clone(),
+            );
+            let transcoded =
+                web::block(move || imaging::transcode(&src, &dst, src_format, &config))
+                    .await
+                    .map_err(actix_web::Error::from)??;
+            if !transcoded {
+                return Ok(());
+            }
+
+            let tmp = std::mem::replace(&mut upload.tmp, converted);
+            if self.config.keep_original {
+                let (sha1, _) =
+                    move_by_hash(tmp, &*upload.storage, &upload.extension, &upload.mime_type)
+                        .await?;
+                upload.original = Some(Original {
+                    sha1,
+                    extension: upload.extension.clone(),
+                });
+            }
+            upload.format = Some(target);
+            upload.extension = imaging::format_extension(target).to_string();
+            upload.mime_type = imaging::format_mime_type(target).to_string();
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sniff;
+    use crate::staging::{stage_test_file, test_path};
+    use crate::storage::FsStorage;
+
+    fn upload(data: &[u8], declared: &str, detected: &'static str) -> Upload {
+        let dir = test_path("pipeline");
+        Upload {
+            tmp: stage_test_file(&dir, data),
+            storage: Arc::new(FsStorage::new(&dir)),
+            length: data.len() as u64,
+            declared_mime_type: declared.to_string(),
+            detected_mime_type: detected,
+            mime_mismatch: !sniff::is_compatible(declared, detected),
+            mime_type: declared.to_string(),
+            format: None,
+            extension: "bin".to_string(),
+            exif: None,
+            original: None,
+            infected: None,
+        }
+    }
+
+    fn filter(allowed: &[&str]) -> MimeFilter {
+        MimeFilter {
+            allowed: allowed.iter().map(|a| a.to_string()).collect(),
+            denied: vec!["text/html".to_string()],
+        }
+    }
+
+    /// A custom step limiting uploads to `max` bytes.
+    struct MaxLength(u64);
+
+    impl Validator for MaxLength {
+        fn name(&self) -> &'static str {
+            "max_length"
+        }
+
+        fn validate<'a>(&'a self, upload: &'a Upload) -> LocalBoxFuture<'a, Result<(), MyError>> {
+            Box::pin(async move { check_size(upload.length, self.0 as usize) })
+        }
+    }
+
+    /// A custom step renaming the extension.
+    struct Rename(&'static str);
+
+    impl Processor for Rename {
+        fn name(&self) -> &'static str {
+            "rename"
+        }
+
+        fn process<'a>(
+            &'a self,
+            upload: &'a mut Upload,
+        ) -> LocalBoxFuture<'a, Result<(), MyError>> {
+            Box::pin(async move {
+                upload.extension = self.0.to_string();
+                Ok(())
+            })
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_mime_check() {
+        let check = MimeCheck {
+            policy: MismatchPolicy::Reject,
+            filter: filter(&[]),
+        };
+        let pdf = upload(b"%PDF-1.4", "application/pdf", "application/pdf");
+        assert!(check.validate(&pdf).await.is_ok());
+        let html = upload(b"<html></html>", "image/png", "text/html");
+        assert!(check.validate(&html).await.is_err());
+
+        // flagged files are checked as what they are
+        let check = MimeCheck {
+            policy: MismatchPolicy::Flag,
+            filter: filter(&["image"]),
+        };
+        let mut html = html;
+        assert!(check.validate(&html).await.is_err());
+        html.mime_type = "text/plain".to_string();
+        html.detected_mime_type = "text/plain";
+        assert!(check.validate(&html).await.is_err());
+        assert!(check.validate(&pdf).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_run() {
+        let steps = vec![
+            Step::Validate(Box::new(MaxLength(64))),
+            Step::Process(Box::new(Rename("txt"))),
+            Step::Process(Box::new(SanitizeSvg)),
+        ];
+        assert_eq!(format!("{:?}", steps), "[max_length, rename, svg]");
+
+        let mut small = upload(b"hello", "text/plain", "text/plain");
+        let path = small.tmp.path().to_owned();
+        run(&steps, &mut small, &Tracker::none()).await.unwrap();
+        assert_eq!(small.extension, "txt");
+        drop(small);
+        assert!(!std::path::Path::new(&path).exists());
+
+        let mut large = upload(&[b'a'; 100], "text/plain", "text/plain");
+        assert!(run(&steps, &mut large, &Tracker::none()).await.is_err());
+
+        let mut svg = upload(
+            b"<svg><script>alert(1)</script></svg>",
+            SVG_MIME_TYPE,
+            SVG_MIME_TYPE,
+        );
+        let path = svg.tmp.path().to_owned();
+        run(&steps, &mut svg, &Tracker::none()).await.unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("script"));
+        drop(svg);
+    }
+
+    #[test]
+    fn test_check_steps() {
+        let names = |list: &str| list.split(',').map(str::to_string).collect::<Vec<_>>();
+        assert!(check_steps(&names("size,mime,svg,image")).is_ok());
+        assert!(check_steps(&names("mime,size,scan,image,svg,transcode")).is_ok());
+        assert_eq!(
+            check_steps(&names("size,mime,svg,image,resize")).unwrap_err(),
+            "unknown pipeline step: resize"
+        );
+        assert_eq!(
+            check_steps(&names("size,mime,image")).unwrap_err(),
+            "missing required pipeline step: svg"
+        );
+    }
+}
@@ -0,0 +1,257 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageOutputFormat};
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::io::Cursor;
+
+use crate::config::dir_flag;
+use crate::error::{img_error, MyError};
+
+/// Number of BlurHash components along the x and y axis.
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+/// Images are scaled down to fit this box before the BlurHash and dominant color
+/// are computed, the result is practically the same and much faster.
+const SAMPLE_SIZE: u32 = 64;
+
+/// Size of the box the tiny LQIP thumbnail fits in.
+const LQIP_SIZE: u32 = 16;
+
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Which placeholders are generated for images of an output directory.
+#[derive(Debug, Clone)]
+pub(crate) struct PlaceholderConfig {
+    /// Also generate the base64 LQIP thumbnail.
+    pub lqip: bool,
+}
+
+impl PlaceholderConfig {
+    /// Reads the settings of an output directory from `PLACEHOLDER` and
+    /// `PLACEHOLDER_LQIP`, `None` when placeholders are disabled.
+    pub(crate) fn from_env(dir_index: Option<&str>) -> Option<Self> {
+        if !dir_flag("PLACEHOLDER", dir_index) {
+            return None;
+        }
+        Some(Self {
+            lqip: dir_flag("PLACEHOLDER_LQIP", dir_index),
+        })
+    }
+}
+
+/// Placeholders shown while an image is loading.
+#[derive(Debug, Clone, serde::Serialize)]
+pub(crate) struct Placeholder {
+    pub blurhash: String,
+    /// Most common color as `#rrggbb`.
+    pub dominant_color: String,
+    /// Tiny thumbnail as a `data:` URI.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lqip: Option<String>,
+}
+
+/// Generates the placeholders of a decoded image.
+///
+/// This is CPU bound and should be run on a blocking thread.
+pub(crate) fn generate(
+    image: &DynamicImage,
+    config: &PlaceholderConfig,
+) -> Result<Placeholder, MyError> {
+    let sample = image.thumbnail(SAMPLE_SIZE, SAMPLE_SIZE);
+    let lqip = if config.lqip {
+        Some(lqip(image)?)
+    } else {
+        None
+    };
+    Ok(Placeholder {
+        blurhash: blurhash(&sample, BLURHASH_COMPONENTS.0, BLURHASH_COMPONENTS.1),
+        dominant_color: dominant_color(&sample),
+        lqip,
+    })
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let v = value as f32 / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u32 {
+    let v = value.clamp(0.0, 1.0);
+    if v <= 0.003_130_8 {
+        (v * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * v.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+fn sign_pow(value: f32, exp: f32) -> f32 {
+    value.abs().powf(exp).copysign(value)
+}
+
+fn encode_base83(value: u32, length: u32, out: &mut String) {
+    for i in 1..=length {
+        let digit = (value / 83u32.pow(length - i)) % 83;
+        out.push(BASE83[digit as usize] as char);
+    }
+}
+
+/// Encodes `image` as a BlurHash with the given number of components, see
+/// https://github.com/woltapp/blurhash/blob/master/Algorithm.md.
+pub(crate) fn blurhash(image: &DynamicImage, components_x: u32, components_y: u32) -> String {
+    let rgb = image.to_rgb8();
+    let (width, height) = rgb.dimensions();
+
+    let mut factors = Vec::with_capacity((components_x * components_y) as usize);
+    for j in 0..components_y {
+        for i in 0..components_x {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0f32; 3];
+            for (x, y, pixel) in rgb.enumerate_pixels() {
+                let basis = normalisation
+                    * (PI * i as f32 * x as f32 / width as f32).cos()
+                    * (PI * j as f32 * y as f32 / height as f32).cos();
+                for (c, value) in factor.iter_mut().zip(pixel.0) {
+                    *c += basis * srgb_to_linear(value);
+                }
+            }
+            let scale = 1.0 / (width * height) as f32;
+            factors.push(factor.map(|c| c * scale));
+        }
+    }
+
+    let mut hash = String::new();
+    encode_base83((components_x - 1) + (components_y - 1) * 9, 1, &mut hash);
+
+    let (dc, ac) = factors.split_first().expect("at least one component");
+    let maximum = if ac.is_empty() {
+        encode_base83(0, 1, &mut hash);
+        1.0
+    } else {
+        let actual = ac.iter().flatten().fold(0f32, |max, c| max.max(c.abs()));
+        let quantised = (actual * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+        encode_base83(quantised, 1, &mut hash);
+        (quantised + 1) as f32 / 166.0
+    };
+
+    let dc_value =
+        (linear_to_srgb(dc[0]) << 16) + (linear_to_srgb(dc[1]) << 8) + linear_to_srgb(dc[2]);
+    encode_base83(dc_value, 4, &mut hash);
+
+    for factor in ac {
+        let [r, g, b] = factor.map(|c| {
+            (sign_pow(c / maximum, 0.5) * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        });
+        encode_base83(r * 19 * 19 + g * 19 + b, 2, &mut hash);
+    }
+    hash
+}
+
+/// Finds the most common color of `image`, colors are grouped into buckets and the
+/// average of the largest bucket is returned. Transparent pixels are ignored.
+pub(crate) fn dominant_color(image: &DynamicImage) -> String {
+    let mut buckets: HashMap<(u8, u8, u8), (u32, [u32; 3])> = HashMap::new();
+    for pixel in image.to_rgba8().pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a < 128 {
+            continue;
+        }
+        let bucket = buckets.entry((r >> 4, g >> 4, b >> 4)).or_default();
+        bucket.0 += 1;
+        bucket.1[0] += r as u32;
+        bucket.1[1] += g as u32;
+        bucket.1[2] += b as u32;
+    }
+
+    // ties are broken by the bucket key so the result is deterministic
+    let [r, g, b] = buckets
+        .into_iter()
+        .max_by_key(|(key, (count, _))| (*count, *key))
+        .map(|(_, (count, sum))| sum.map(|c| c / count))
+        .unwrap_or_default();
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Encodes a tiny thumbnail of `image` as a `data:` URI, PNG for images with
+/// transparency and JPEG otherwise.
+fn lqip(image: &DynamicImage) -> Result<String, MyError> {
+    let thumbnail = image.resize(LQIP_SIZE, LQIP_SIZE, FilterType::Triangle);
+    let mut data = Vec::new();
+    let mime_type = if thumbnail.color().has_alpha() {
+        thumbnail
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .map_err(img_error)?;
+        "image/png"
+    } else {
+        DynamicImage::ImageRgb8(thumbnail.to_rgb8())
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Jpeg(50))
+            .map_err(img_error)?;
+        "image/jpeg"
+    };
+    Ok(format!(
+        "data:{};base64,{}",
+        mime_type,
+        STANDARD.encode(data)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    #[test]
+    fn test_blurhash() {
+        let image = RgbImage::from_fn(4, 4, |x, y| Rgb([x as u8 * 60, y as u8 * 60, 128]));
+        assert_eq!(
+            blurhash(&DynamicImage::ImageRgb8(image), 4, 3),
+            "LNDJPB3=A=~D.+IdN]z|dxeXfQeX"
+        );
+    }
+
+    #[test]
+    fn test_dominant_color() {
+        // a quarter is transparent, a quarter blue and the rest red
+        let image = RgbaImage::from_fn(4, 4, |x, y| match (x, y) {
+            (0..=1, 0..=1) => Rgba([0, 0, 0, 0]),
+            (2..=3, 0..=1) => Rgba([0, 0, 255, 255]),
+            _ => Rgba([200, 10, 10, 255]),
+        });
+        assert_eq!(dominant_color(&DynamicImage::ImageRgba8(image)), "#c80a0a");
+    }
+
+    #[test]
+    fn test_generate() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(100, 50, Rgb([10, 20, 30])));
+        let placeholder = generate(&image, &PlaceholderConfig { lqip: true }).unwrap();
+        assert_eq!(placeholder.blurhash.len(), 28);
+        assert_eq!(placeholder.dominant_color, "#0a141e");
+        assert!(placeholder
+            .lqip
+            .unwrap()
+            .starts_with("data:image/jpeg;base64,/9j/"));
+    }
+}
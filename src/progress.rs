@@ -0,0 +1,409 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use actix_web::error::{ErrorBadRequest, ErrorConflict, ErrorServiceUnavailable};
+use actix_web::web::Bytes;
+use actix_web::{http::header, HttpRequest};
+use futures::{Stream, StreamExt};
+use log::{debug, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::error::MyError;
+
+/// Default time the status of a finished upload is kept.
+const DEFAULT_TTL: u64 = 600;
+
+/// Default number of uploads tracked.
+const DEFAULT_MAX_UPLOADS: usize = 10_000;
+
+/// Longest accepted `X-Upload-Id`.
+const MAX_ID_LEN: usize = 64;
+
+/// Stages of an upload.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum State {
+    /// The file is being received.
+    Receiving,
+    /// The file is being checked.
+    Validating,
+    /// The file is being scanned, converted or stored.
+    Processing,
+    Done,
+    Failed,
+}
+
+/// Status of an upload returned by `GET /uploads/{id}`.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Status {
+    pub id: String,
+    pub state: State,
+    /// Pipeline step being run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<&'static str>,
+    pub bytes_received: u64,
+    /// Size of the request from `Content-Length`, when known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_total: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip)]
+    finished_at: Option<Instant>,
+}
+
+impl Status {
+    pub(crate) fn is_finished(&self) -> bool {
+        matches!(self.state, State::Done | State::Failed)
+    }
+}
+
+/// Status of the uploads in progress and recently finished, kept in memory and
+/// shared by the workers.
+pub(crate) struct Progress {
+    ttl: Duration,
+    /// Most uploads tracked, the uploads that finished first make room for new ones.
+    max_uploads: usize,
+    uploads: Mutex<HashMap<String, Arc<watch::Sender<Status>>>>,
+}
+
+impl Progress {
+    pub(crate) fn new(ttl: Duration, max_uploads: usize) -> Self {
+        Self {
+            ttl,
+            max_uploads,
+            uploads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reads the time the status of finished uploads is kept from
+    /// `UPLOAD_STATUS_TTL` in seconds, and the most uploads tracked from
+    /// `UPLOAD_STATUS_MAX`.
+    pub(crate) fn from_env() -> Self {
+        let number = |name: &str, default| {
+            env::var(name)
+                .ok()
+                .and_then(|a| {
+                    a.trim().parse().ok().or_else(|| {
+                        warn!("Invalid {}: {}", name, a);
+                        None
+                    })
+                })
+                .unwrap_or(default)
+        };
+        Self::new(
+            Duration::from_secs(number("UPLOAD_STATUS_TTL", DEFAULT_TTL as usize) as u64),
+            number("UPLOAD_STATUS_MAX", DEFAULT_MAX_UPLOADS),
+        )
+    }
+
+    /// Forgets the uploads that finished more than the ttl ago.
+    fn prune(&self, uploads: &mut HashMap<String, Arc<watch::Sender<Status>>>) {
+        let now = Instant::now();
+        uploads.retain(|_, upload| {
+            upload
+                .borrow()
+                .finished_at
+                .is_none_or(|finished_at| now.duration_since(finished_at) < self.ttl)
+        });
+    }
+
+    /// Starts tracking the upload of a request, identified by its `X-Upload-Id`
+    /// header or a generated id.
+    ///
+    /// An id that is used by an upload in progress is rejected with
+    /// `409 Conflict`, and an upload with `503 Service Unavailable` when all the
+    /// tracked uploads are in progress.
+    pub(crate) fn start(&self, req: &HttpRequest) -> Result<Tracker, MyError> {
+        let id = match req.headers().get("X-Upload-Id") {
+            Some(id) => id
+                .to_str()
+                .ok()
+                .filter(|a| valid_id(a))
+                .ok_or_else(|| ErrorBadRequest("Invalid X-Upload-Id header"))?
+                .to_owned(),
+            None => generate_id()?,
+        };
+        let bytes_total = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|a| a.to_str().ok())
+            .and_then(|a| a.parse().ok());
+
+        let mut uploads = self.uploads.lock().unwrap();
+        self.prune(&mut uploads);
+        if uploads.get(&id).is_some_and(|a| !a.borrow().is_finished()) {
+            return Err(ErrorConflict("An upload with this X-Upload-Id is in progress").into());
+        }
+        if !uploads.contains_key(&id) && uploads.len() >= self.max_uploads {
+            // make room by dropping the upload that finished first
+            let oldest = uploads
+                .iter()
+                .filter_map(|(id, upload)| Some((upload.borrow().finished_at?, id.clone())))
+                .min();
+            match oldest {
+                Some((_, oldest)) => {
+                    uploads.remove(&oldest);
+                }
+                None => return Err(ErrorServiceUnavailable("Too many uploads in progress").into()),
+            }
+        }
+        debug!("tracking upload {}", id);
+        let (sender, _) = watch::channel(Status {
+            id: id.clone(),
+            state: State::Receiving,
+            step: None,
+            bytes_received: 0,
+            bytes_total,
+            sha1: None,
+            error: None,
+            finished_at: None,
+        });
+        let sender = Arc::new(sender);
+        uploads.insert(id.clone(), sender.clone());
+        Ok(Tracker {
+            id,
+            sender: Some(sender),
+        })
+    }
+
+    /// Current status of the upload `id`.
+    pub(crate) fn status(&self, id: &str) -> Option<Status> {
+        let mut uploads = self.uploads.lock().unwrap();
+        self.prune(&mut uploads);
+        uploads.get(id).map(|a| a.borrow().clone())
+    }
+
+    /// Server-Sent Events with the status of the upload `id` whenever it changes,
+    /// ending once the upload is finished.
+    pub(crate) fn events(
+        &self,
+        id: &str,
+    ) -> Option<impl Stream<Item = Result<Bytes, actix_web::Error>>> {
+        let receiver = self.uploads.lock().unwrap().get(id)?.subscribe();
+        // the receiver, whether it has been read and whether the upload finished
+        Some(futures::stream::unfold(
+            (receiver, false, false),
+            |(mut receiver, read, finished)| async move {
+                // the status can't change anymore once the upload has finished or
+                // its tracker is gone
+                if finished || (read && receiver.changed().await.is_err()) {
+                    return None;
+                }
+                let status = receiver.borrow_and_update().clone();
+                let event = format!(
+                    "data: {}\n\n",
+                    serde_json::to_string(&status).unwrap_or_default()
+                );
+                let finished = status.is_finished();
+                Some((Ok(Bytes::from(event)), (receiver, true, finished)))
+            },
+        ))
+    }
+}
+
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn generate_id() -> Result<String, MyError> {
+    let mut id = [0u8; 16];
+    getrandom::getrandom(&mut id).map_err(std::io::Error::other)?;
+    Ok(hex::encode(id))
+}
+
+/// Reports the progress of an upload. An upload whose tracker is dropped before
+/// it finished, e.g. because the client disconnected, is marked as failed.
+pub(crate) struct Tracker {
+    pub id: String,
+    sender: Option<Arc<watch::Sender<Status>>>,
+}
+
+impl Tracker {
+    /// A tracker that reports nothing.
+    #[cfg(test)]
+    pub(crate) fn none() -> Self {
+        Self {
+            id: String::new(),
+            sender: None,
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Status)) {
+        if let Some(sender) = &self.sender {
+            sender.send_modify(f);
+        }
+    }
+
+    /// Sets the expected size of the upload.
+    pub(crate) fn total(&self, bytes_total: Option<u64>) {
+        self.update(|status| status.bytes_total = bytes_total);
+    }
+
+    /// Adds `n` bytes to those received.
+    pub(crate) fn received(&self, n: usize) {
+        self.update(|status| status.bytes_received += n as u64);
+    }
+
+    /// Counts the bytes of `stream` as received, for the stream `bytes_total` is
+    /// the size of.
+    pub(crate) fn count<'a, S, E>(&'a self, stream: S) -> impl Stream<Item = Result<Bytes, E>> + 'a
+    where
+        S: Stream<Item = Result<Bytes, E>> + 'a,
+    {
+        stream.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                self.received(chunk.len());
+            }
+        })
+    }
+
+    pub(crate) fn state(&self, state: State) {
+        self.update(|status| {
+            status.state = state;
+            status.step = None;
+        });
+    }
+
+    /// Reports the pipeline step being run.
+    pub(crate) fn step(&self, state: State, step: &'static str) {
+        self.update(|status| {
+            status.state = state;
+            status.step = Some(step);
+        });
+    }
+
+    pub(crate) fn done(&self, sha1: &str) {
+        self.finish(State::Done, Some(sha1), None);
+    }
+
+    /// Marks the upload as failed when `result` is an error.
+    pub(crate) fn report<T>(&self, result: Result<T, MyError>) -> Result<T, MyError> {
+        if let Err(e) = &result {
+            self.finish(State::Failed, None, Some(&e.to_string()));
+        }
+        result
+    }
+
+    fn finish(&self, state: State, sha1: Option<&str>, error: Option<&str>) {
+        self.update(|status| {
+            if status.is_finished() {
+                return;
+            }
+            status.state = state;
+            status.step = None;
+            status.sha1 = sha1.map(str::to_owned);
+            status.error = error.map(str::to_owned);
+            status.finished_at = Some(Instant::now());
+        });
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        self.finish(State::Failed, None, Some("Upload interrupted"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use actix_web::ResponseError;
+    use futures::StreamExt;
+
+    #[actix_rt::test]
+    async fn test_progress() {
+        let progress = Progress::new(Duration::from_secs(60), 100);
+        let req = TestRequest::post()
+            .insert_header(("X-Upload-Id", "upload-1"))
+            .insert_header((header::CONTENT_LENGTH, "10"))
+            .to_http_request();
+
+        let tracker = progress.start(&req).unwrap();
+        let mut events = Box::pin(progress.events("upload-1").unwrap());
+        assert!(progress.start(&req).is_err());
+        tracker.received(4);
+        tracker.received(6);
+        let status = progress.status("upload-1").unwrap();
+        assert_eq!(
+            (status.state, status.bytes_received),
+            (State::Receiving, 10)
+        );
+        assert_eq!(status.bytes_total, Some(10));
+
+        let event = events.next().await.unwrap().unwrap();
+        assert!(event.starts_with(b"data: {\"id\":\"upload-1\",\"state\":\"receiving\""));
+
+        tracker.step(State::Processing, "scan");
+        let event = events.next().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&event).contains("\"step\":\"scan\""));
+
+        tracker.done("abc");
+        let event = events.next().await.unwrap().unwrap();
+        assert!(String::from_utf8_lossy(&event).contains("\"state\":\"done\""));
+        assert!(events.next().await.is_none());
+
+        // finished ids can be reused, dropped uploads fail
+        let tracker = progress.start(&req).unwrap();
+        drop(tracker);
+        let status = progress.status("upload-1").unwrap();
+        assert_eq!(status.state, State::Failed);
+        assert_eq!(status.error.as_deref(), Some("Upload interrupted"));
+
+        assert!(progress.status("other").is_none());
+        let req = TestRequest::post()
+            .insert_header(("X-Upload-Id", "../x"))
+            .to_http_request();
+        assert!(progress.start(&req).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_progress_limits() {
+        let start = |progress: &Progress, id: &str| {
+            let req = TestRequest::post()
+                .insert_header(("X-Upload-Id", id))
+                .to_http_request();
+            progress.start(&req)
+        };
+
+        // the upload that finished first makes room, uploads in progress don't
+        let progress = Progress::new(Duration::from_secs(60), 2);
+        let first = start(&progress, "first").unwrap();
+        let second = start(&progress, "second").unwrap();
+        let error = start(&progress, "third").err().unwrap();
+        assert_eq!(error.error_response().status(), 503);
+        first.done("abc");
+        second.done("def");
+        let _third = start(&progress, "third").unwrap();
+        assert!(progress.status("first").is_none());
+        assert!(progress.status("second").is_some());
+
+        // finished uploads are forgotten after the ttl, also when only polled
+        let progress = Progress::new(Duration::ZERO, 2);
+        start(&progress, "first").unwrap().done("abc");
+        assert!(progress.status("first").is_none());
+    }
+}
@@ -0,0 +1,448 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use actix_web::HttpRequest;
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{self, dir_var, out_dirs};
+use crate::crypto;
+use crate::idempotency;
+use crate::sniff;
+use crate::staging::{self, TmpFile};
+use crate::storage::{self, Storage};
+
+/// Suffix of the default quarantine directory, next to its output directory.
+const QUARANTINE_DIR_SUFFIX: &str = "-quarantine";
+
+/// Default time quarantined uploads are kept for, 30 days.
+const DEFAULT_RETENTION: u64 = 30 * 24 * 60 * 60;
+
+/// Request headers that are never written to a record.
+const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "x-signature",
+];
+
+/// Returns the quarantine directory of an output directory, `QUARANTINE_DIR` or
+/// the hidden `.{name}-quarantine` directory next to it, outside of what is
+/// served.
+pub(crate) fn quarantine_dir(out_dir: &str, dir_index: Option<&str>) -> String {
+    dir_var("QUARANTINE_DIR", dir_index).unwrap_or_else(|| {
+        let path = std::path::absolute(out_dir).unwrap_or_else(|_| PathBuf::from(out_dir));
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => parent
+                .join(format!(
+                    ".{}{}",
+                    name.to_string_lossy(),
+                    QUARANTINE_DIR_SUFFIX
+                ))
+                .to_string_lossy()
+                .into_owned(),
+            _ => format!("{}{}", path.to_string_lossy(), QUARANTINE_DIR_SUFFIX),
+        }
+    })
+}
+
+/// Time quarantined uploads are kept for from `QUARANTINE_RETENTION` in seconds,
+/// `None` keeps them until they are purged.
+fn retention(dir_index: Option<&str>) -> Option<Duration> {
+    let retention = dir_var("QUARANTINE_RETENTION", dir_index)
+        .and_then(|a| {
+            a.trim().parse().ok().or_else(|| {
+                warn!("Invalid QUARANTINE_RETENTION: {}", a);
+                None
+            })
+        })
+        .unwrap_or(DEFAULT_RETENTION);
+    Some(Duration::from_secs(retention)).filter(|a| !a.is_zero())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |a| a.as_secs())
+}
+
+/// Where a rejected upload came from.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Origin {
+    /// Value of the client header, see `IDEMPOTENCY_CLIENT_HEADER`.
+    pub client: Option<String>,
+    pub ip: Option<String>,
+    /// URL of a file imported with `POST /fetch`.
+    pub url: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Origin {
+    /// Collects the origin of the upload sent with `req`, leaving out credentials.
+    pub(crate) fn from_request(req: &HttpRequest) -> Self {
+        let client = req
+            .headers()
+            .get(idempotency::client_header().as_str())
+            .and_then(|a| a.to_str().ok())
+            .map(str::to_owned);
+        let headers = req
+            .headers()
+            .iter()
+            .filter(|(name, _)| !SECRET_HEADERS.contains(&name.as_str()))
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                (name.as_str().to_owned(), value)
+            })
+            .collect();
+        Self {
+            client,
+            ip: req.peer_addr().map(|a| a.ip().to_string()),
+            url: None,
+            headers,
+        }
+    }
+}
+
+/// Describes a quarantined upload, it is kept next to it as `{id}.json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Record {
+    /// SHA1 hash of the upload.
+    pub id: String,
+    pub filename: String,
+    pub size: u64,
+    /// Why the upload was rejected.
+    pub reason: String,
+    /// HTTP status the upload was rejected with.
+    pub status: u16,
+    /// Malware found in the upload.
+    pub signature: Option<String>,
+    pub dir_index: Option<String>,
+    pub declared_mime_type: Option<String>,
+    pub detected_mime_type: Option<String>,
+    pub extension: Option<String>,
+    /// Unix time the upload was rejected at.
+    pub time: u64,
+    #[serde(flatten)]
+    pub origin: Origin,
+}
+
+/// Moves a rejected upload into the quarantine directory `dir`, named by its SHA1
+/// hash, so it is kept for inspection but never served. `record` is written next
+/// to it with its id set to the hash.
+///
+/// Returns the hash.
+pub(crate) fn quarantine(tmp: TmpFile, dir: &str, mut record: Record) -> io::Result<String> {
+    std::fs::create_dir_all(dir)?;
+    let hash = crypto::get_sha1_file(&mut File::open(tmp.path())?)?;
+    warn!("quarantining {}: {}", hash, record.reason);
+    record.id = hash.clone();
+    if record.time == 0 {
+        record.time = now();
+    }
+    std::fs::write(
+        sidecar_path(dir, &hash),
+        serde_json::to_vec_pretty(&record)?,
+    )?;
+    tmp.persist(&format!("{}/{}", dir, hash))?;
+    Ok(hash)
+}
+
+fn sidecar_path(dir: &str, id: &str) -> String {
+    format!("{}/{}.json", dir, id)
+}
+
+/// Ids are SHA1 hashes, anything else could point outside the directory.
+fn is_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(|a| a.is_ascii_hexdigit())
+}
+
+/// Reads the record of the quarantined upload `id` in `dir`, uploads quarantined
+/// without one get a record from the file alone.
+pub(crate) fn read_record(dir: &str, id: &str) -> io::Result<Record> {
+    if !is_id(id) {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    let metadata = std::fs::metadata(format!("{}/{}", dir, id))?;
+    if let Ok(record) = std::fs::read(sidecar_path(dir, id)) {
+        return serde_json::from_slice(&record).map_err(io::Error::from);
+    }
+    Ok(Record {
+        id: id.to_owned(),
+        size: metadata.len(),
+        time: metadata
+            .modified()
+            .ok()
+            .and_then(|a| a.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |a| a.as_secs()),
+        ..Default::default()
+    })
+}
+
+/// Lists the uploads quarantined in `dir`, oldest first.
+pub(crate) fn list(dir: &str) -> io::Result<Vec<Record>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut records = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name();
+        let Some(id) = name.to_str().filter(|a| is_id(a)) else {
+            continue;
+        };
+        records.push(read_record(dir, id)?);
+    }
+    records.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.id.cmp(&b.id)));
+    Ok(records)
+}
+
+/// Deletes the quarantined upload `id` in `dir` and its record.
+pub(crate) fn purge(dir: &str, id: &str) -> io::Result<()> {
+    if !is_id(id) {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    std::fs::remove_file(format!("{}/{}", dir, id))?;
+    remove_sidecar(dir, id)
+}
+
+fn remove_sidecar(dir: &str, id: &str) -> io::Result<()> {
+    match std::fs::remove_file(sidecar_path(dir, id)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Moves the quarantined upload `id` in `dir` into `storage` as
+/// `{id}.{extension}`, as it was received. It is copied to `staging` first, so it
+/// stays in quarantine when it can't be stored.
+///
+/// Returns the key of the released file.
+pub(crate) async fn release(
+    dir: &str,
+    id: &str,
+    storage: &dyn Storage,
+    staging: &str,
+) -> io::Result<String> {
+    let record = read_record(dir, id)?;
+    let key = format!("{}.{}", id, record.extension.as_deref().unwrap_or("bin"));
+    let (tmp, _) = TmpFile::create(staging, "release")?;
+    std::fs::copy(format!("{}/{}", dir, id), tmp.path())?;
+    let content_type = record
+        .detected_mime_type
+        .as_deref()
+        .unwrap_or(sniff::OCTET_STREAM);
+    storage.put(tmp, &key, content_type).await?;
+    purge(dir, id)?;
+    Ok(key)
+}
+
+/// Deletes the uploads quarantined in `dir` for longer than `retention`.
+pub(crate) fn sweep_dir(dir: &str, retention: Duration) -> io::Result<usize> {
+    let expired_at = now().saturating_sub(retention.as_secs());
+    let mut removed = 0;
+    for record in list(dir)? {
+        if record.time <= expired_at {
+            debug!("removing expired quarantined upload: {}", record.id);
+            purge(dir, &record.id)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Quarantine directories of every output directory, with the index of the first
+/// output directory using each.
+fn quarantine_dirs() -> Vec<(String, Option<String>)> {
+    let mut dirs: Vec<(String, Option<String>)> = Vec::new();
+    for (out_dir, index) in out_dirs() {
+        let dir = quarantine_dir(&out_dir, index.as_deref());
+        if !dirs.iter().any(|(a, _)| *a == dir) {
+            dirs.push((dir, index));
+        }
+    }
+    dirs
+}
+
+/// Deletes expired uploads from the quarantine directory of every output directory.
+pub(crate) fn sweep_all() {
+    for (dir, index) in quarantine_dirs() {
+        let Some(retention) = retention(index.as_deref()) else {
+            continue;
+        };
+        match sweep_dir(&dir, retention) {
+            Ok(0) => {}
+            Ok(n) => info!("removed {} expired quarantined uploads from {}", n, dir),
+            Err(e) => warn!("Failed to sweep {}: {}", dir, e),
+        }
+    }
+}
+
+/// Commands to manage quarantined uploads.
+#[derive(Subcommand, Debug)]
+pub(crate) enum QuarantineCommand {
+    /// Lists the quarantined uploads.
+    List,
+    /// Prints the record of a quarantined upload.
+    Inspect { id: String },
+    /// Moves a quarantined upload into an output directory.
+    Release {
+        id: String,
+        /// Index of the output directory, the one it was rejected from by default.
+        #[arg(long)]
+        dir_index: Option<String>,
+    },
+    /// Deletes quarantined uploads.
+    Purge {
+        #[arg(required_unless_present_any = ["all", "expired"])]
+        ids: Vec<String>,
+        /// Deletes every quarantined upload.
+        #[arg(long, conflicts_with = "expired")]
+        all: bool,
+        /// Deletes the uploads older than `QUARANTINE_RETENTION`.
+        #[arg(long)]
+        expired: bool,
+    },
+}
+
+/// Finds the quarantine directory holding the upload `id`.
+fn find(id: &str) -> Result<(String, Record)> {
+    quarantine_dirs()
+        .into_iter()
+        .find_map(|(dir, _)| read_record(&dir, id).ok().map(|record| (dir, record)))
+        .ok_or_else(|| anyhow!("No quarantined upload {}", id))
+}
+
+/// Runs a quarantine command, printing its result.
+pub(crate) async fn run_command(command: QuarantineCommand) -> Result<()> {
+    match command {
+        QuarantineCommand::List => {
+            for (dir, _) in quarantine_dirs() {
+                for record in list(&dir)? {
+                    println!(
+                        "{}\t{}\t{}\t{}\t{}\t{}",
+                        record.id,
+                        record.time,
+                        record.dir_index.as_deref().unwrap_or("-"),
+                        record.size,
+                        record.filename,
+                        record.reason
+                    );
+                }
+            }
+        }
+        QuarantineCommand::Inspect { id } => {
+            let (_, record) = find(&id)?;
+            println!("{}", serde_json::to_string_pretty(&record)?);
+        }
+        QuarantineCommand::Release { id, dir_index } => {
+            let (dir, record) = find(&id)?;
+            let index = dir_index.or(record.dir_index);
+            let out_dir = config::out_dir(index.as_deref()).ok_or_else(|| {
+                anyhow!("Unknown dir index: {}", index.clone().unwrap_or_default())
+            })?;
+            let storage = storage::from_env(&out_dir, index.as_deref()).map_err(|e| anyhow!(e))?;
+            let staging = staging::staging_dir(&out_dir, index.as_deref());
+            println!("{}", release(&dir, &id, &*storage, &staging).await?);
+        }
+        QuarantineCommand::Purge { ids, all, expired } => {
+            let mut removed = 0;
+            for (dir, index) in quarantine_dirs() {
+                if all {
+                    for record in list(&dir)? {
+                        purge(&dir, &record.id)?;
+                        removed += 1;
+                    }
+                } else if expired {
+                    if let Some(retention) = retention(index.as_deref()) {
+                        removed += sweep_dir(&dir, retention)?;
+                    }
+                }
+            }
+            for id in ids {
+                let (dir, _) = find(&id)?;
+                purge(&dir, &id)?;
+                removed += 1;
+            }
+            println!("removed {} quarantined uploads", removed);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staging::{stage_test_file, test_path};
+    use crate::storage::FsStorage;
+
+    #[actix_rt::test]
+    async fn test_quarantine() {
+        let root = &test_path("quarantine");
+        let _ = std::fs::remove_dir_all(root);
+        let dir = format!("{}/.quarantine", root);
+
+        let tmp = stage_test_file(root, b"rejected");
+        let record = Record {
+            filename: "a.txt".into(),
+            reason: "File type not allowed".into(),
+            status: 415,
+            extension: Some("txt".into()),
+            ..Default::default()
+        };
+        let id = quarantine(tmp, &dir, record).unwrap();
+
+        let records = list(&dir).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, id);
+        assert_eq!(records[0].reason, "File type not allowed");
+        assert!(read_record(&dir, "../a").is_err());
+
+        // kept until they are older than the retention
+        assert_eq!(sweep_dir(&dir, Duration::from_secs(60)).unwrap(), 0);
+
+        let storage = FsStorage::new(root);
+        let staging = format!("{}/.staging", root);
+        let released = release(&dir, &id, &storage, &staging).await.unwrap();
+        assert_eq!(released, format!("{}.txt", id));
+        let released = format!("{}/{}", root, released);
+        assert_eq!(std::fs::read(released).unwrap(), b"rejected");
+        assert!(list(&dir).unwrap().is_empty());
+
+        let (tmp, _) = TmpFile::create(root, "b.txt").unwrap();
+        quarantine(tmp, &dir, Record::default()).unwrap();
+        assert_eq!(sweep_dir(&dir, Duration::ZERO).unwrap(), 1);
+        assert!(list(&dir).unwrap().is_empty());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_quarantine_dir() {
+        assert_eq!(
+            quarantine_dir("/srv/images/", None),
+            "/srv/.images-quarantine"
+        );
+        assert_eq!(quarantine_dir("/", None), "/-quarantine");
+    }
+}
@@ -0,0 +1,278 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use log::{debug, warn};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::{dir_flag, dir_var};
+
+/// Size of the chunks a file is streamed to clamd in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Default timeout for a whole scan.
+const DEFAULT_TIMEOUT: u64 = 60;
+
+/// Address of a clamd daemon.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ClamdAddress {
+    /// `host:port`
+    Tcp(String),
+    /// Path of a Unix socket.
+    Unix(String),
+}
+
+impl ClamdAddress {
+    /// Parses `tcp://host:port`, `unix:///path`, a plain `host:port` or an absolute
+    /// socket path.
+    fn parse(address: &str) -> Self {
+        let address = address.trim();
+        if let Some(path) = address.strip_prefix("unix://") {
+            Self::Unix(path.to_owned())
+        } else if let Some(host) = address.strip_prefix("tcp://") {
+            Self::Tcp(host.to_owned())
+        } else if address.starts_with('/') {
+            Self::Unix(address.to_owned())
+        } else {
+            Self::Tcp(address.to_owned())
+        }
+    }
+}
+
+/// Malware scanning settings of an output directory.
+#[derive(Debug, Clone)]
+pub(crate) struct ScanConfig {
+    pub address: ClamdAddress,
+    pub timeout: Duration,
+    /// Accept files when clamd can't be reached instead of rejecting them.
+    pub fail_open: bool,
+}
+
+impl ScanConfig {
+    /// Reads the settings of an output directory from `CLAMD_ADDRESS`,
+    /// `CLAMD_TIMEOUT` (seconds) and `CLAMD_FAIL_OPEN`, `None` when scanning is
+    /// disabled.
+    pub(crate) fn from_env(dir_index: Option<&str>) -> Option<Self> {
+        let address = ClamdAddress::parse(&dir_var("CLAMD_ADDRESS", dir_index)?);
+        let timeout = dir_var("CLAMD_TIMEOUT", dir_index)
+            .and_then(|a| {
+                a.trim().parse().ok().or_else(|| {
+                    warn!("Invalid CLAMD_TIMEOUT: {}", a);
+                    None
+                })
+            })
+            .unwrap_or(DEFAULT_TIMEOUT);
+        Some(Self {
+            address,
+            timeout: Duration::from_secs(timeout),
+            fail_open: dir_flag("CLAMD_FAIL_OPEN", dir_index),
+        })
+    }
+}
+
+/// Outcome of a scan.
+#[derive(Debug, PartialEq)]
+pub(crate) enum ScanResult {
+    Clean,
+    /// Name of the signature that matched.
+    Infected(String),
+}
+
+/// Scans the file at `path` by streaming it to clamd with the `INSTREAM` command.
+pub(crate) async fn scan_file(path: &str, config: &ScanConfig) -> io::Result<ScanResult> {
+    let scan = async {
+        let file = tokio::fs::File::open(path).await?;
+        match &config.address {
+            ClamdAddress::Tcp(addr) => {
+                instream(tokio::net::TcpStream::connect(addr).await?, file).await
+            }
+            #[cfg(unix)]
+            ClamdAddress::Unix(path) => {
+                instream(tokio::net::UnixStream::connect(path).await?, file).await
+            }
+            #[cfg(not(unix))]
+            ClamdAddress::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    };
+    let reply = actix_rt::time::timeout(config.timeout, scan)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "clamd scan timed out"))??;
+    debug!("clamd: {}", reply);
+    parse_reply(&reply)
+}
+
+/// Sends `file` in length prefixed chunks, terminated by an empty chunk, and reads
+/// the reply.
+async fn instream<S, F>(mut stream: S, mut file: F) -> io::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: AsyncRead + Unpin,
+{
+    stream.write_all(b"zINSTREAM\0").await?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let sent: io::Result<()> = async {
+        loop {
+            let n = file.read(&mut buf).await?;
+            stream.write_all(&(n as u32).to_be_bytes()).await?;
+            if n == 0 {
+                return stream.flush().await;
+            }
+            stream.write_all(&buf[..n]).await?;
+        }
+    }
+    .await;
+
+    // clamd replies and closes the connection when the stream is too large, so
+    // the reply is read even when sending failed
+    let mut reply = Vec::new();
+    let read = stream.read_to_end(&mut reply).await;
+    if reply.is_empty() {
+        sent?;
+        read?;
+    }
+    let reply = String::from_utf8_lossy(&reply);
+    Ok(reply.trim_end_matches(['\0', '\n']).to_owned())
+}
+
+/// Parses a reply such as `stream: OK` or `stream: Eicar-Signature FOUND`.
+fn parse_reply(reply: &str) -> io::Result<ScanResult> {
+    let status = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if status == "OK" {
+        Ok(ScanResult::Clean)
+    } else if let Some(signature) = status.strip_suffix("FOUND") {
+        Ok(ScanResult::Infected(signature.trim().to_owned()))
+    } else {
+        Err(io::Error::other(format!("clamd error: {}", reply)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staging::{test_file, test_path};
+
+    /// Test signature, a file containing it is reported as infected.
+    const EICAR: &[u8] = b"X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+    /// Answers a single `INSTREAM` scan the way clamd does.
+    async fn serve_scan<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) {
+        let mut command = [0u8; 10];
+        stream.read_exact(&mut command).await.unwrap();
+        assert_eq!(&command, b"zINSTREAM\0");
+        let mut data = Vec::new();
+        loop {
+            let mut len = [0u8; 4];
+            stream.read_exact(&mut len).await.unwrap();
+            let len = u32::from_be_bytes(len) as usize;
+            if len == 0 {
+                break;
+            }
+            let mut chunk = vec![0u8; len];
+            stream.read_exact(&mut chunk).await.unwrap();
+            data.extend(chunk);
+        }
+        let reply: &[u8] = if data.windows(EICAR.len()).any(|w| w == EICAR) {
+            b"stream: Eicar-Test-Signature FOUND\0"
+        } else {
+            b"stream: OK\0"
+        };
+        stream.write_all(reply).await.unwrap();
+    }
+
+    /// Starts a stand-in for clamd on a local TCP port.
+    async fn serve_clamd() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        actix_rt::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                actix_rt::spawn(serve_scan(stream));
+            }
+        });
+        addr
+    }
+
+    fn config(address: ClamdAddress) -> ScanConfig {
+        ScanConfig {
+            address,
+            timeout: Duration::from_secs(5),
+            fail_open: false,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            ClamdAddress::parse("tcp://clamd:3310"),
+            ClamdAddress::Tcp("clamd:3310".to_string())
+        );
+        assert_eq!(
+            ClamdAddress::parse("unix:///run/clamd.sock"),
+            ClamdAddress::Unix("/run/clamd.sock".to_string())
+        );
+        assert_eq!(parse_reply("stream: OK").unwrap(), ScanResult::Clean);
+        assert_eq!(
+            parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND").unwrap(),
+            ScanResult::Infected("Win.Test.EICAR_HDB-1".to_string())
+        );
+        assert!(parse_reply("INSTREAM size limit exceeded. ERROR").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_scan_tcp() {
+        let config = config(ClamdAddress::Tcp(serve_clamd().await));
+
+        let clean = test_file("clean.txt", &vec![b'a'; 200_000]);
+        assert_eq!(scan_file(&clean, &config).await.unwrap(), ScanResult::Clean);
+
+        let infected = test_file("eicar.txt", EICAR);
+        assert_eq!(
+            scan_file(&infected, &config).await.unwrap(),
+            ScanResult::Infected("Eicar-Test-Signature".to_string())
+        );
+
+        // nothing listens on the port anymore
+        let config = self::config(ClamdAddress::Tcp("127.0.0.1:1".to_string()));
+        assert!(scan_file(&clean, &config).await.is_err());
+
+        let _ = std::fs::remove_file(clean);
+        let _ = std::fs::remove_file(infected);
+    }
+
+    #[cfg(unix)]
+    #[actix_rt::test]
+    async fn test_scan_unix() {
+        let socket = test_path("clamd.sock");
+        let _ = std::fs::remove_file(&socket);
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        actix_rt::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve_scan(stream).await;
+        });
+
+        let infected = test_file("eicar-unix.txt", EICAR);
+        let config = config(ClamdAddress::Unix(socket.clone()));
+        assert!(matches!(
+            scan_file(&infected, &config).await.unwrap(),
+            ScanResult::Infected(_)
+        ));
+
+        let _ = std::fs::remove_file(infected);
+        let _ = std::fs::remove_file(socket);
+    }
+}
@@ -0,0 +1,580 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use log::warn;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use actix_web::error::ErrorUnsupportedMediaType;
+
+use crate::config::dir_var;
+use crate::error::MyError;
+use crate::imaging;
+
+/// Number of bytes at the start of a file the signatures are matched against.
+const HEAD_LEN: usize = 8192;
+
+/// Number of bytes at the end of a ZIP that are searched for the names of its
+/// entries, the central directory is at the end of the archive.
+const ZIP_TAIL_LEN: u64 = 65536;
+
+pub(crate) const OCTET_STREAM: &str = "application/octet-stream";
+const TEXT_PLAIN: &str = "text/plain";
+const ZIP: &str = "application/zip";
+const OLE: &str = "application/x-ole-storage";
+
+/// Types that are detected from their content, with the extension files of the
+/// type are stored with.
+const TYPES: &[(&str, &str)] = &[
+    ("application/pdf", "pdf"),
+    (ZIP, "zip"),
+    (
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "docx",
+    ),
+    (
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "xlsx",
+    ),
+    (
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "pptx",
+    ),
+    ("application/vnd.oasis.opendocument.text", "odt"),
+    ("application/vnd.oasis.opendocument.spreadsheet", "ods"),
+    ("application/vnd.oasis.opendocument.presentation", "odp"),
+    ("application/epub+zip", "epub"),
+    ("application/java-archive", "jar"),
+    ("application/vnd.android.package-archive", "apk"),
+    (OLE, "ole"),
+    ("application/gzip", "gz"),
+    ("application/x-7z-compressed", "7z"),
+    ("application/vnd.rar", "rar"),
+    ("application/rtf", "rtf"),
+    ("video/mp4", "mp4"),
+    ("video/quicktime", "mov"),
+    ("video/3gpp", "3gp"),
+    ("audio/mp4", "m4a"),
+    ("video/webm", "webm"),
+    ("video/x-matroska", "mkv"),
+    ("video/x-msvideo", "avi"),
+    ("audio/mpeg", "mp3"),
+    ("audio/wav", "wav"),
+    ("audio/ogg", "ogg"),
+    ("audio/flac", "flac"),
+    ("application/x-msdownload", "exe"),
+    ("application/x-executable", "elf"),
+    ("application/x-mach-binary", "macho"),
+    ("application/x-sh", "sh"),
+    ("text/html", "html"),
+    ("application/xhtml+xml", "xhtml"),
+    ("image/svg+xml", "svg"),
+    ("application/xml", "xml"),
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("image/bmp", "bmp"),
+    ("image/tiff", "tiff"),
+    ("image/x-icon", "ico"),
+    ("image/avif", "avif"),
+    (TEXT_PLAIN, "txt"),
+];
+
+/// Types that name the same content, or content a sniffer can't tell apart.
+const ALIASES: &[&[&str]] = &[
+    &[
+        ZIP,
+        "application/x-zip-compressed",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "application/vnd.oasis.opendocument.text",
+        "application/vnd.oasis.opendocument.spreadsheet",
+        "application/vnd.oasis.opendocument.presentation",
+        "application/epub+zip",
+        "application/java-archive",
+        "application/vnd.android.package-archive",
+    ],
+    &[
+        OLE,
+        "application/msword",
+        "application/vnd.ms-excel",
+        "application/vnd.ms-powerpoint",
+        "application/vnd.ms-outlook",
+        "application/x-msi",
+    ],
+    &[
+        "video/mp4",
+        "video/quicktime",
+        "video/3gpp",
+        "audio/mp4",
+        "audio/x-m4a",
+        "video/x-m4v",
+    ],
+    &["video/webm", "video/x-matroska", "audio/webm"],
+    &["audio/mpeg", "audio/mp3"],
+    &["audio/wav", "audio/x-wav", "audio/wave"],
+    &["audio/ogg", "application/ogg", "video/ogg"],
+    &["audio/flac", "audio/x-flac"],
+    &["application/gzip", "application/x-gzip"],
+    &["application/vnd.rar", "application/x-rar-compressed"],
+    &["application/rtf", "text/rtf"],
+    &[
+        "application/x-sh",
+        "text/x-shellscript",
+        "application/x-shellscript",
+    ],
+    &["application/xml", "text/xml"],
+    &[
+        "application/x-msdownload",
+        "application/x-dosexec",
+        "application/vnd.microsoft.portable-executable",
+    ],
+    &["image/jpeg", "image/jpg", "image/pjpeg"],
+    &["image/x-icon", "image/vnd.microsoft.icon"],
+    &["image/bmp", "image/x-ms-bmp"],
+];
+
+/// What happens to an upload whose content doesn't match its declared type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MismatchPolicy {
+    /// The upload is rejected.
+    Reject,
+    /// The upload is stored as the detected type and flagged in the response.
+    Flag,
+}
+
+impl MismatchPolicy {
+    /// Reads the policy of an output directory from `MIME_MISMATCH`, `reject` by
+    /// default.
+    pub(crate) fn from_env(dir_index: Option<&str>) -> Self {
+        match dir_var("MIME_MISMATCH", dir_index)
+            .map(|a| a.trim().to_ascii_lowercase())
+            .as_deref()
+        {
+            None | Some("reject") => Self::Reject,
+            Some("flag") => Self::Flag,
+            Some(other) => {
+                warn!("Invalid MIME_MISMATCH: {}, rejecting mismatches", other);
+                Self::Reject
+            }
+        }
+    }
+}
+
+/// Types denied in every output directory unless `DENIED_TYPES` is set.
+const DEFAULT_DENIED_TYPES: &str = "application/x-msdownload,application/x-executable,\
+    application/x-mach-binary,application/x-sh,text/html,application/xhtml+xml,application/xml,\
+    text/xml";
+
+/// MIME types an output directory accepts.
+#[derive(Debug, Clone)]
+pub(crate) struct MimeFilter {
+    /// Accepted types or families, any type is accepted when empty.
+    pub allowed: Vec<String>,
+    /// Rejected types or families, these win over `allowed`.
+    pub denied: Vec<String>,
+}
+
+impl MimeFilter {
+    /// Reads the filter of an output directory from `ALLOWED_TYPES` and
+    /// `DENIED_TYPES`, e.g. `ALLOWED_TYPES_docs=application/pdf,image`.
+    /// `DENIED_TYPES=none` accepts every type.
+    pub(crate) fn from_env(dir_index: Option<&str>) -> Self {
+        let allowed = dir_var("ALLOWED_TYPES", dir_index)
+            .map(|a| Self::parse_list(&a))
+            .unwrap_or_default();
+        let denied = dir_var("DENIED_TYPES", dir_index)
+            .map(|a| Self::parse_list(&a))
+            .unwrap_or_else(|| Self::parse_list(DEFAULT_DENIED_TYPES));
+        Self { allowed, denied }
+    }
+
+    fn parse_list(list: &str) -> Vec<String> {
+        list.split(',')
+            .map(|a| a.trim().trim_end_matches("/*").to_ascii_lowercase())
+            .filter(|a| !a.is_empty() && a != "none")
+            .collect()
+    }
+
+    fn matches(list: &[String], mime_type: &str) -> bool {
+        let family = mime_type.split('/').next().unwrap_or_default();
+        list.iter().any(|a| a == mime_type || a == family)
+    }
+
+    /// Rejects a file of `mime_type` with `415 Unsupported Media Type` when the
+    /// directory doesn't accept it.
+    pub(crate) fn check(&self, mime_type: &str) -> Result<(), MyError> {
+        if !(self.allowed.is_empty() || Self::matches(&self.allowed, mime_type)) {
+            return Err(not_allowed(mime_type));
+        }
+        self.check_denied(mime_type)
+    }
+
+    /// Like [`check`](Self::check) but only rejects denied types.
+    pub(crate) fn check_denied(&self, mime_type: &str) -> Result<(), MyError> {
+        if Self::matches(&self.denied, mime_type) {
+            return Err(not_allowed(mime_type));
+        }
+        Ok(())
+    }
+}
+
+fn not_allowed(mime_type: &str) -> MyError {
+    ErrorUnsupportedMediaType(format!("File type {} is not allowed", mime_type)).into()
+}
+
+/// Returns the extension files of a detected type are stored with.
+pub(crate) fn extension(mime_type: &str) -> Option<&'static str> {
+    TYPES
+        .iter()
+        .find(|(mime, _)| *mime == mime_type)
+        .map(|(_, ext)| *ext)
+}
+
+/// Returns the extensions of the detected types, those of images first.
+pub(crate) fn extensions() -> impl Iterator<Item = &'static str> {
+    let (images, others): (Vec<&(&str, &str)>, Vec<_>) = TYPES
+        .iter()
+        .partition(|(mime, _)| mime.starts_with("image/"));
+    images.into_iter().chain(others).map(|&(_, ext)| ext)
+}
+
+/// Detects the type of the file at `path` from its content,
+/// `application/octet-stream` when it is not known.
+pub(crate) fn sniff_file(path: &str) -> io::Result<&'static str> {
+    let mut file = File::open(path)?;
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    Read::by_ref(&mut file)
+        .take(HEAD_LEN as u64)
+        .read_to_end(&mut head)?;
+
+    let mut tail = Vec::new();
+    if head.starts_with(b"PK\x03\x04") {
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(len.saturating_sub(ZIP_TAIL_LEN)))?;
+        file.read_to_end(&mut tail)?;
+    }
+    Ok(sniff(&head, &tail))
+}
+
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Detects a type from the first bytes of a file, `tail` holds the end of ZIP
+/// archives.
+pub(crate) fn sniff(head: &[u8], tail: &[u8]) -> &'static str {
+    if let Ok(format) = image::guess_format(head) {
+        let mime = imaging::format_mime_type(format);
+        if mime != OCTET_STREAM {
+            return mime;
+        }
+    }
+
+    // the header may be preceded by up to 1024 bytes of garbage
+    if contains(&head[..head.len().min(1024)], b"%PDF-") {
+        return "application/pdf";
+    }
+    if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+        return sniff_zip(head, tail);
+    }
+
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\xD0\xCF\x11\xE0\xA1\xB1\x1A\xE1", OLE),
+        (b"\x1F\x8B", "application/gzip"),
+        (b"7z\xBC\xAF\x27\x1C", "application/x-7z-compressed"),
+        (b"Rar!\x1A\x07", "application/vnd.rar"),
+        (b"{\\rtf", "application/rtf"),
+        (b"ID3", "audio/mpeg"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"MZ", "application/x-msdownload"),
+        (b"\x7FELF", "application/x-executable"),
+        (b"\xFE\xED\xFA\xCE", "application/x-mach-binary"),
+        (b"\xFE\xED\xFA\xCF", "application/x-mach-binary"),
+        (b"\xCE\xFA\xED\xFE", "application/x-mach-binary"),
+        (b"\xCF\xFA\xED\xFE", "application/x-mach-binary"),
+        (b"\xCA\xFE\xBA\xBE", "application/x-mach-binary"),
+        (b"#!", "application/x-sh"),
+    ];
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(sig, _)| head.starts_with(sig)) {
+        return mime;
+    }
+
+    if head.len() >= 12 && &head[..4] == b"RIFF" {
+        match &head[8..12] {
+            b"WAVE" => return "audio/wav",
+            b"AVI " => return "video/x-msvideo",
+            _ => {}
+        }
+    }
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return match &head[8..12] {
+            b"qt  " => "video/quicktime",
+            b"M4A " | b"M4B " => "audio/mp4",
+            brand if brand.starts_with(b"3g") => "video/3gpp",
+            _ => "video/mp4",
+        };
+    }
+    if head.starts_with(b"\x1A\x45\xDF\xA3") {
+        return if contains(head, b"webm") {
+            "video/webm"
+        } else {
+            "video/x-matroska"
+        };
+    }
+    // MPEG audio frame sync without an ID3 tag
+    if head.len() >= 2 && head[0] == 0xFF && head[1] & 0xE6 == 0xE2 {
+        return "audio/mpeg";
+    }
+
+    sniff_text(head)
+}
+
+fn sniff_zip(head: &[u8], tail: &[u8]) -> &'static str {
+    // ODF and EPUB store their type uncompressed in a first entry named mimetype
+    if head.get(30..38) == Some(b"mimetype") {
+        const MIMETYPES: &[&str] = &[
+            "application/vnd.oasis.opendocument.text",
+            "application/vnd.oasis.opendocument.spreadsheet",
+            "application/vnd.oasis.opendocument.presentation",
+            "application/epub+zip",
+        ];
+        if let Some(mime) = MIMETYPES
+            .iter()
+            .find(|m| head[38..].starts_with(m.as_bytes()))
+        {
+            return mime;
+        }
+    }
+
+    // otherwise by the names of the entries
+    const ENTRIES: &[(&[u8], &str)] = &[
+        (
+            b"word/",
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        ),
+        (
+            b"xl/",
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        ),
+        (
+            b"ppt/",
+            "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        ),
+        (
+            b"AndroidManifest.xml",
+            "application/vnd.android.package-archive",
+        ),
+        (b"META-INF/MANIFEST.MF", "application/java-archive"),
+    ];
+    ENTRIES
+        .iter()
+        .find(|(name, _)| contains(head, name) || contains(tail, name))
+        .map_or(ZIP, |(_, mime)| mime)
+}
+
+fn sniff_text(head: &[u8]) -> &'static str {
+    // a multi byte character may be cut off at the end
+    let valid = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    if !valid || head.contains(&0) {
+        return OCTET_STREAM;
+    }
+
+    let text = String::from_utf8_lossy(head).to_ascii_lowercase();
+    let mut text = text.trim_start_matches('\u{feff}').trim_start();
+    // skip the prolog: the XML declaration, processing instructions, the doctype and
+    // comments, e.g. the generator comment of SVG editors
+    let mut prolog = false;
+    let mut html_doctype = false;
+    loop {
+        let end = if text.starts_with("<!--") {
+            text.find("-->").map(|end| end + 3)
+        } else if text.starts_with("<?") {
+            prolog = true;
+            text.find("?>").map(|end| end + 2)
+        } else if text.starts_with("<!doctype") {
+            prolog = true;
+            html_doctype |= text.starts_with("<!doctype html");
+            // the doctype may have an internal subset in brackets
+            match (text.find('['), text.find('>')) {
+                (Some(open), Some(close)) if open < close => text.find("]>").map(|end| end + 2),
+                (_, close) => close.map(|end| end + 1),
+            }
+        } else {
+            break;
+        };
+        match end {
+            Some(end) => text = text[end..].trim_start(),
+            // the root element is beyond the head
+            None => {
+                text = "";
+                break;
+            }
+        }
+    }
+
+    // the root element, without a namespace prefix
+    let root = text
+        .strip_prefix('<')
+        .map(|tag| {
+            let name = tag
+                .split(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/')
+                .next()
+                .unwrap_or_default();
+            name.rsplit(':').next().unwrap_or_default()
+        })
+        .unwrap_or_default();
+    if root == "svg" {
+        return "image/svg+xml";
+    }
+    // XHTML elements run scripts wherever they are in an XML document
+    if !root.is_empty() && text.contains("http://www.w3.org/1999/xhtml") {
+        return "application/xhtml+xml";
+    }
+    const HTML: &[&str] = &["html", "head", "body", "script", "iframe"];
+    if html_doctype || HTML.contains(&root) {
+        return "text/html";
+    }
+    if prolog {
+        return "application/xml";
+    }
+    TEXT_PLAIN
+}
+
+/// Returns true when content detected as `detected` may be uploaded as
+/// `declared`.
+///
+/// Nothing is checked for uploads without a declared type, and content that isn't
+/// recognized, or is plain text, is only rejected for types that have a signature.
+pub(crate) fn is_compatible(declared: &str, detected: &str) -> bool {
+    let known = |mime: &str| {
+        TYPES.iter().any(|(m, _)| *m == mime && mime != TEXT_PLAIN)
+            || ALIASES.iter().any(|group| group.contains(&mime))
+    };
+
+    declared == detected
+        || declared == OCTET_STREAM
+        || ((detected == OCTET_STREAM || detected == TEXT_PLAIN) && !known(declared))
+        || ALIASES
+            .iter()
+            .any(|group| group.contains(&declared) && group.contains(&detected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b"%PDF-1.7\n", b""), "application/pdf");
+        assert_eq!(
+            sniff(b"MZ\x90\0\x03\0\0\0", b""),
+            "application/x-msdownload"
+        );
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42\0\0\0\0", b""), "video/mp4");
+        assert_eq!(sniff(b"ID3\x03\0\0\0\0", b""), "audio/mpeg");
+        assert_eq!(
+            sniff(b"PK\x03\x04\x14\0", b"PK\x01\x02word/document.xml"),
+            "application/vnd.openxmlformats-officedocument.wordprocessingml.document"
+        );
+        assert_eq!(sniff(b"PK\x03\x04\x14\0", b"PK\x01\x02readme.txt"), ZIP);
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR", b""), "image/png");
+        assert_eq!(sniff(b"  <!DOCTYPE html><html>", b""), "text/html");
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?><svg>", b""), "image/svg+xml");
+        assert_eq!(sniff(b"<!-- Generator: x --><svg>", b""), "image/svg+xml");
+        assert_eq!(sniff("name,price\ncaf\u{e9},1".as_bytes(), b""), TEXT_PLAIN);
+        assert_eq!(sniff(b"\x01\x02\x00\x03", b""), OCTET_STREAM);
+    }
+
+    #[test]
+    fn test_sniff_xml() {
+        let denied = MimeFilter {
+            allowed: Vec::new(),
+            denied: MimeFilter::parse_list(DEFAULT_DENIED_TYPES),
+        };
+
+        let xhtml = b"<?xml version=\"1.0\"?><html xmlns=\"http://www.w3.org/1999/xhtml\">\
+            <script>alert(1)</script></html>";
+        assert_eq!(sniff(xhtml, b""), "application/xhtml+xml");
+        let nested = b"<?xml version=\"1.0\"?><feed><x:script \
+            xmlns:x=\"http://www.w3.org/1999/xhtml\">alert(1)</x:script></feed>";
+        assert_eq!(sniff(nested, b""), "application/xhtml+xml");
+        assert!(denied.check("application/xhtml+xml").is_err());
+
+        let svg = b"<?xml version=\"1.0\"?><!DOCTYPE svg [<!ENTITY a \"b\">]>\
+            <!-- <html> --><svg:svg xmlns:svg=\"http://www.w3.org/2000/svg\"/>";
+        assert_eq!(sniff(svg, b""), "image/svg+xml");
+        assert_eq!(sniff(b"<!DOCTYPE html><p>", b""), "text/html");
+
+        // an SVG padded beyond the head can't be told from other XML, which is denied
+        let mut padded = b"<?xml version=\"1.0\"?><!--".to_vec();
+        padded.resize(HEAD_LEN + 10, b'a');
+        padded.extend_from_slice(b"--><svg><script>alert(1)</script></svg>");
+        assert_eq!(sniff(&padded[..HEAD_LEN], b""), "application/xml");
+        assert!(denied.check("application/xml").is_err());
+        assert!(denied.check("text/xml").is_err());
+        assert_eq!(
+            sniff(b"<?xml version=\"1.0\"?><feed/>", b""),
+            "application/xml"
+        );
+    }
+
+    #[test]
+    fn test_mime_filter() {
+        let filter = MimeFilter {
+            allowed: MimeFilter::parse_list("application/pdf, image/*"),
+            denied: MimeFilter::parse_list("image/svg+xml"),
+        };
+        assert!(filter.check("application/pdf").is_ok());
+        assert!(filter.check("image/png").is_ok());
+        assert!(filter.check("image/svg+xml").is_err());
+        assert!(filter.check("text/plain").is_err());
+
+        let filter = MimeFilter {
+            allowed: Vec::new(),
+            denied: MimeFilter::parse_list(DEFAULT_DENIED_TYPES),
+        };
+        assert!(filter.check("text/plain").is_ok());
+        assert!(filter.check("application/x-msdownload").is_err());
+        assert!(filter.check("text/html").is_err());
+        assert!(MimeFilter::parse_list("none").is_empty());
+    }
+
+    #[test]
+    fn test_is_compatible() {
+        assert!(is_compatible("application/pdf", "application/pdf"));
+        assert!(!is_compatible(
+            "application/pdf",
+            "application/x-msdownload"
+        ));
+        assert!(!is_compatible("application/pdf", OCTET_STREAM));
+        assert!(!is_compatible("text/plain", "text/html"));
+        assert!(is_compatible("application/msword", OLE));
+        assert!(is_compatible("video/quicktime", "video/mp4"));
+        assert!(is_compatible("text/csv", TEXT_PLAIN));
+        assert!(is_compatible("application/x-foo", OCTET_STREAM));
+        assert!(is_compatible(OCTET_STREAM, "application/x-msdownload"));
+        assert!(!is_compatible(
+            "application/x-foo",
+            "application/x-msdownload"
+        ));
+    }
+}
@@ -0,0 +1,251 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use log::{debug, info, warn};
+use std::env;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use crate::config::{dir_var, out_dirs};
+use crate::nonce;
+
+/// Name of the staging directory inside an output directory.
+const STAGING_DIR_NAME: &str = ".staging";
+
+/// Default age after which files left in a staging directory are deleted.
+const DEFAULT_MAX_AGE: u64 = 3600;
+
+/// Default interval between sweeps of the staging directories.
+const DEFAULT_SWEEP_INTERVAL: u64 = 600;
+
+/// Makes the names of temporary files unique within the process.
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the staging directory of an output directory, `STAGING_DIR` or the
+/// `.staging` directory inside it. It must be on the same filesystem as the
+/// output directory so files can be renamed into place.
+pub(crate) fn staging_dir(out_dir: &str, dir_index: Option<&str>) -> String {
+    dir_var("STAGING_DIR", dir_index).unwrap_or_else(|| format!("{}/{}", out_dir, STAGING_DIR_NAME))
+}
+
+/// A file in a staging directory that is deleted when dropped, unless it has been
+/// moved into place with [`TmpFile::persist`].
+///
+/// Dropping covers every failure path, including uploads whose future is dropped
+/// because the client disconnected.
+#[derive(Debug)]
+pub(crate) struct TmpFile {
+    path: String,
+    armed: bool,
+}
+
+impl TmpFile {
+    /// Creates a new empty file in `dir` with a unique name ending in `suffix`.
+    pub(crate) fn create(dir: &str, suffix: &str) -> io::Result<(Self, File)> {
+        std::fs::create_dir_all(dir)?;
+        let path = format!(
+            "{}/tmp-{}-{}-{}",
+            dir,
+            nonce::nonce(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            suffix
+        );
+        debug!("tmp file: {}", path);
+        let file = File::create(&path)?;
+        Ok((Self { path, armed: true }, file))
+    }
+
+    /// A file next to this one with `extension` appended to the name, e.g. for the
+    /// output of a conversion. The file is not created.
+    pub(crate) fn sibling(&self, extension: &str) -> Self {
+        Self {
+            path: format!("{}.{}", self.path, extension),
+            armed: true,
+        }
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Flushes the file to disk and renames it to `dst`, or copies it when `dst` is
+    /// on another filesystem.
+    pub(crate) fn persist(mut self, dst: &str) -> io::Result<()> {
+        File::open(&self.path)?.sync_all()?;
+        if std::fs::rename(&self.path, dst).is_ok() {
+            self.armed = false;
+        } else {
+            std::fs::copy(&self.path, dst)?;
+            File::open(dst)?.sync_all()?;
+        }
+        // make the rename itself durable
+        if let Some(parent) = Path::new(dst).parent() {
+            if let Ok(dir) = File::open(parent) {
+                let _ = dir.sync_all();
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes the file to disk and moves it to `dst` unless there already is a
+    /// file there, in one step so concurrent uploads of the same content can't
+    /// both claim it. Returns whether `dst` already existed, the file is deleted
+    /// either way.
+    pub(crate) fn persist_new(self, dst: &str) -> io::Result<bool> {
+        File::open(&self.path)?.sync_all()?;
+        match std::fs::hard_link(&self.path, dst) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(true),
+            Err(e) => return Err(e),
+        }
+        if let Some(parent) = Path::new(dst).parent() {
+            if let Ok(dir) = File::open(parent) {
+                let _ = dir.sync_all();
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Drop for TmpFile {
+    fn drop(&mut self) {
+        if self.armed {
+            match std::fs::remove_file(&self.path) {
+                Ok(()) => debug!("removed tmp file: {}", self.path),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Failed to remove {}: {}", self.path, e),
+            }
+        }
+    }
+}
+
+/// Deletes the `tmp-*` files in `dir` older than `max_age`, returns how many were
+/// deleted.
+pub(crate) fn sweep_dir(dir: &str, max_age: Duration) -> io::Result<usize> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with("tmp-") {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .unwrap_or_default();
+        if metadata.is_file() && age >= max_age {
+            debug!("removing stale tmp file: {:?}", entry.path());
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Deletes stale tmp files from the staging directory of every output directory,
+/// and those left inside the output directories by older versions.
+pub(crate) fn sweep_all() {
+    let max_age = Duration::from_secs(
+        env::var("STAGING_MAX_AGE")
+            .ok()
+            .and_then(|a| a.trim().parse().ok())
+            .unwrap_or(DEFAULT_MAX_AGE),
+    );
+
+    for (out_dir, index) in out_dirs() {
+        for dir in [staging_dir(&out_dir, index.as_deref()), out_dir] {
+            match sweep_dir(&dir, max_age) {
+                Ok(0) => {}
+                Ok(n) => info!("removed {} stale tmp files from {}", n, dir),
+                Err(e) => warn!("Failed to sweep {}: {}", dir, e),
+            }
+        }
+    }
+}
+
+/// Interval between sweeps from `STAGING_SWEEP_INTERVAL` in seconds.
+pub(crate) fn sweep_interval() -> Duration {
+    Duration::from_secs(
+        env::var("STAGING_SWEEP_INTERVAL")
+            .ok()
+            .and_then(|a| a.trim().parse().ok())
+            .filter(|a| *a > 0)
+            .unwrap_or(DEFAULT_SWEEP_INTERVAL),
+    )
+}
+
+/// Returns a path in the system temp directory that is unique to the test process.
+#[cfg(test)]
+pub(crate) fn test_path(name: &str) -> String {
+    let path = env::temp_dir().join(format!("rantang-test-{}-{}", std::process::id(), name));
+    path.to_str().unwrap().to_owned()
+}
+
+/// Writes `data` to the test path `name` and returns the path.
+#[cfg(test)]
+pub(crate) fn test_file(name: &str, data: &[u8]) -> String {
+    let path = test_path(name);
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+/// Stages `data` in `dir` as an upload would be.
+#[cfg(test)]
+pub(crate) fn stage_test_file(dir: &str, data: &[u8]) -> TmpFile {
+    let (tmp, mut f) = TmpFile::create(dir, "test").unwrap();
+    io::Write::write_all(&mut f, data).unwrap();
+    tmp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tmp_file() {
+        let dir = &test_path("staging");
+
+        let (tmp, _) = TmpFile::create(dir, "a.txt").unwrap();
+        let path = tmp.path().to_owned();
+        assert!(Path::new(&path).exists());
+        drop(tmp);
+        assert!(!Path::new(&path).exists());
+
+        let (tmp, _) = TmpFile::create(dir, "b.txt").unwrap();
+        let dst = format!("{}/kept.txt", dir);
+        tmp.persist(&dst).unwrap();
+        assert!(Path::new(&dst).exists());
+
+        let (stale, _) = TmpFile::create(dir, "c.txt").unwrap();
+        let stale_path = stale.path().to_owned();
+        std::mem::forget(stale);
+        assert_eq!(sweep_dir(dir, Duration::from_secs(3600)).unwrap(), 0);
+        assert_eq!(sweep_dir(dir, Duration::ZERO).unwrap(), 1);
+        assert!(!Path::new(&stale_path).exists());
+        assert!(Path::new(&dst).exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,397 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use actix_web::web;
+use futures::future::{try_join_all, LocalBoxFuture};
+use log::debug;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::config::{dir_flag, dir_var, out_dirs, parse_size};
+use crate::s3::{S3Config, S3Storage};
+use crate::sniff;
+use crate::staging::TmpFile;
+
+/// Where stored files end up. Files are addressed by keys such as
+/// `{sha1}.{extension}`, relative to the output directory. Storages are shared by
+/// the workers.
+pub(crate) trait Storage: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Stores the staged file as `key`, unless there already is a file with that
+    /// key. The staged file is deleted either way. `content_type` is the MIME type
+    /// the file is served with, by the storages that keep one.
+    ///
+    /// Returns whether `key` already existed.
+    fn put<'a>(
+        &'a self,
+        tmp: TmpFile,
+        key: &'a str,
+        content_type: &'a str,
+    ) -> LocalBoxFuture<'a, io::Result<bool>>;
+
+    fn exists<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, io::Result<bool>>;
+
+    /// Reads the file stored as `key`, `None` when there is none.
+    fn get<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, io::Result<Option<Vec<u8>>>>;
+
+    /// Deletes the file stored as `key`, if there is one.
+    fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, io::Result<()>>;
+}
+
+/// Prefix of the index that maps the hash of a stored file to its extension, so
+/// the file can be found by its hash alone.
+const INDEX_PREFIX: &str = ".index/";
+
+/// MIME type of the index entries.
+pub(crate) const INDEX_CONTENT_TYPE: &str = "text/plain";
+
+/// Extensions of files found without an index entry besides those of the detected
+/// types. `json` goes last, images have a `.json` sidecar next to them.
+const OTHER_EXTENSIONS: &[&str] = &["jpeg", "csv", "json"];
+
+/// Key of the index entry of the file stored under `hash`.
+pub(crate) fn index_key(hash: &str) -> String {
+    format!("{}{}", INDEX_PREFIX, hash)
+}
+
+/// Writes to `tmp` the index entry of a file stored with `extension`.
+pub(crate) async fn stage_index(tmp: &TmpFile, extension: &str) -> io::Result<()> {
+    let path = tmp.path().to_owned();
+    let extension = extension.to_owned();
+    block(move || std::fs::write(path, extension)).await
+}
+
+/// Records the extension of the file stored as `{hash}.{extension}`, `tmp` is
+/// where the entry is staged. The entry is written before the file, so a file is
+/// never stored without it.
+///
+/// Returns whether the entry already existed.
+pub(crate) async fn put_index(
+    storage: &dyn Storage,
+    tmp: TmpFile,
+    hash: &str,
+    extension: &str,
+) -> io::Result<bool> {
+    stage_index(&tmp, extension).await?;
+    storage.put(tmp, &index_key(hash), INDEX_CONTENT_TYPE).await
+}
+
+/// Returns the extension of the file stored under `hash`.
+///
+/// Files stored before the index existed, or whose entry outlived them, are
+/// looked for with the extensions of the detected types.
+pub(crate) async fn find_extension(
+    storage: &dyn Storage,
+    hash: &str,
+) -> io::Result<Option<String>> {
+    if let Some(entry) = storage.get(&index_key(hash)).await? {
+        let extension = String::from_utf8_lossy(&entry).trim().to_owned();
+        if storage.exists(&format!("{}.{}", hash, extension)).await? {
+            return Ok(Some(extension));
+        }
+    }
+    let extensions: Vec<&str> = sniff::extensions()
+        .chain(OTHER_EXTENSIONS.iter().copied())
+        .collect();
+    let keys: Vec<String> = extensions
+        .iter()
+        .map(|extension| format!("{}.{}", hash, extension))
+        .collect();
+    let found = try_join_all(keys.iter().map(|key| storage.exists(key))).await?;
+    Ok(extensions
+        .into_iter()
+        .zip(found)
+        .find(|(_, found)| *found)
+        .map(|(extension, _)| extension.to_owned()))
+}
+
+/// Default capacity of a memory storage.
+const DEFAULT_MEMORY_CAPACITY: usize = 64 * 1024 * 1024;
+
+/// Builds the storage of an output directory from `STORAGE`, `fs` (the default),
+/// `s3` or `memory`.
+pub(crate) fn from_env(out_dir: &str, dir_index: Option<&str>) -> Result<Arc<dyn Storage>, String> {
+    let storage = dir_var("STORAGE", dir_index).unwrap_or_else(|| "fs".to_string());
+    match storage.trim().to_ascii_lowercase().as_str() {
+        "fs" => Ok(Arc::new(FsStorage::new(out_dir))),
+        "s3" => Ok(Arc::new(S3Storage::new(S3Config::from_env(dir_index)?)?)),
+        "memory" => {
+            let capacity = dir_var("MEMORY_STORAGE_CAPACITY", dir_index)
+                .map(|a| {
+                    parse_size(&a).ok_or_else(|| format!("Invalid MEMORY_STORAGE_CAPACITY: {}", a))
+                })
+                .transpose()?
+                .unwrap_or(DEFAULT_MEMORY_CAPACITY);
+            Ok(Arc::new(MemoryStorage::new(capacity)))
+        }
+        other => Err(format!("Unknown STORAGE: {}", other)),
+    }
+}
+
+/// The output directories by dir index with their storages, built once at startup
+/// and shared by the workers.
+#[derive(Default)]
+pub(crate) struct Storages {
+    dirs: BTreeMap<Option<String>, (String, Arc<dyn Storage>)>,
+}
+
+impl Storages {
+    /// Builds the storage of every output directory, see [`from_env`].
+    pub(crate) fn from_env() -> Result<Self, String> {
+        let mut storages = Self::default();
+        for (dir, index) in out_dirs() {
+            let storage = from_env(&dir, index.as_deref())
+                .map_err(|e| format!("Invalid storage of {}: {}", dir, e))?;
+            // confirmed uploads are renamed into the directory
+            if storage.name() != "fs" && dir_flag("CONFIRM_UPLOADS", index.as_deref()) {
+                return Err(format!(
+                    "Invalid storage of {}: CONFIRM_UPLOADS needs the fs storage",
+                    dir
+                ));
+            }
+            debug!("storage of {}: {}", dir, storage.name());
+            storages.insert(index, dir, storage);
+        }
+        Ok(storages)
+    }
+
+    /// Adds the output directory `path` with the index `dir_index`.
+    pub(crate) fn insert(
+        &mut self,
+        dir_index: Option<String>,
+        path: String,
+        storage: Arc<dyn Storage>,
+    ) {
+        self.dirs.insert(dir_index, (path, storage));
+    }
+
+    /// The path and the storage of the output directory with the index `dir_index`.
+    pub(crate) fn get(&self, dir_index: Option<&str>) -> Option<(String, Arc<dyn Storage>)> {
+        self.dirs.get(&dir_index.map(str::to_owned)).cloned()
+    }
+}
+
+/// Stores files in a local directory, the output directory itself by default.
+pub(crate) struct FsStorage {
+    dir: String,
+}
+
+impl FsStorage {
+    pub(crate) fn new(dir: &str) -> Self {
+        Self {
+            dir: dir.to_owned(),
+        }
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("{}/{}", self.dir, key)
+    }
+}
+
+impl Storage for FsStorage {
+    fn name(&self) -> &'static str {
+        "fs"
+    }
+
+    fn put<'a>(
+        &'a self,
+        tmp: TmpFile,
+        key: &'a str,
+        _content_type: &'a str,
+    ) -> LocalBoxFuture<'a, io::Result<bool>> {
+        Box::pin(async move {
+            let path = self.path(key);
+            debug!("old_path: {}", tmp.path());
+            debug!("new_path: {}", path);
+            let existed = block(move || {
+                if let Some(parent) = Path::new(&path).parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let existed = tmp.persist_new(&path)?;
+                if existed {
+                    debug!("{} already exists", path);
+                }
+                Ok(existed)
+            })
+            .await?;
+            Ok(existed)
+        })
+    }
+
+    fn exists<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, io::Result<bool>> {
+        let path = self.path(key);
+        Box::pin(async move { block(move || Ok(Path::new(&path).is_file())).await })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, io::Result<Option<Vec<u8>>>> {
+        let path = self.path(key);
+        Box::pin(async move {
+            block(move || match std::fs::read(path) {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            })
+            .await
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, io::Result<()>> {
+        let path = self.path(key);
+        Box::pin(async move {
+            block(move || match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            })
+            .await
+        })
+    }
+}
+
+/// Runs blocking file system work off the event loop.
+pub(crate) async fn block<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.map_err(io::Error::other)?
+}
+
+/// Stores files in memory, up to `capacity` bytes. They are lost on restart, which
+/// suits tests and ephemeral deployments such as preview environments.
+#[derive(Clone)]
+pub(crate) struct MemoryStorage {
+    memory: Arc<Mutex<Memory>>,
+}
+
+struct Memory {
+    files: BTreeMap<String, Vec<u8>>,
+    size: usize,
+    capacity: usize,
+}
+
+impl MemoryStorage {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            memory: Arc::new(Mutex::new(Memory {
+                files: BTreeMap::new(),
+                size: 0,
+                capacity,
+            })),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn put<'a>(
+        &'a self,
+        tmp: TmpFile,
+        key: &'a str,
+        _content_type: &'a str,
+    ) -> LocalBoxFuture<'a, io::Result<bool>> {
+        Box::pin(async move {
+            if self.memory.lock().unwrap().files.contains_key(key) {
+                debug!("{} already exists", key);
+                return Ok(true);
+            }
+            let data = block(move || std::fs::read(tmp.path())).await?;
+            let mut memory = self.memory.lock().unwrap();
+            if memory.files.contains_key(key) {
+                return Ok(true);
+            }
+            if memory.size + data.len() > memory.capacity {
+                return Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    "Memory storage is full",
+                ));
+            }
+            memory.size += data.len();
+            memory.files.insert(key.to_owned(), data);
+            Ok(false)
+        })
+    }
+
+    fn exists<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, io::Result<bool>> {
+        Box::pin(async move { Ok(self.memory.lock().unwrap().files.contains_key(key)) })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, io::Result<Option<Vec<u8>>>> {
+        Box::pin(async move { Ok(self.memory.lock().unwrap().files.get(key).cloned()) })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> LocalBoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut memory = self.memory.lock().unwrap();
+            if let Some(data) = memory.files.remove(key) {
+                memory.size -= data.len();
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staging::{stage_test_file, test_path};
+
+    #[actix_rt::test]
+    async fn test_memory_storage() {
+        let dir = &test_path("memory");
+        let stage = |data: &[u8]| stage_test_file(dir, data);
+        let storage = MemoryStorage::new(8);
+
+        assert!(!storage
+            .put(stage(b"abc"), "a.txt", "text/plain")
+            .await
+            .unwrap());
+        assert!(storage
+            .put(stage(b"abc"), "a.txt", "text/plain")
+            .await
+            .unwrap());
+        assert!(!storage
+            .put(stage(b"def"), "b.txt", "text/plain")
+            .await
+            .unwrap());
+        assert!(storage.exists("b.txt").await.unwrap());
+        assert_eq!(storage.get("a.txt").await.unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(storage.get("c.txt").await.unwrap(), None);
+
+        let full = storage
+            .put(stage(b"ghi"), "c.txt", "text/plain")
+            .await
+            .unwrap_err();
+        assert_eq!(full.kind(), io::ErrorKind::StorageFull);
+        assert!(!storage.exists("c.txt").await.unwrap());
+        // deleted files make room
+        storage.delete("a.txt").await.unwrap();
+        storage.delete("a.txt").await.unwrap();
+        assert_eq!(storage.get("a.txt").await.unwrap(), None);
+        assert!(!storage
+            .put(stage(b"ghi"), "c.txt", "text/plain")
+            .await
+            .unwrap());
+        // staged files are deleted either way
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,487 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use actix_web::error::ErrorBadRequest;
+use log::debug;
+
+use crate::error::MyError;
+
+/// Elements that are removed together with their content.
+const DROPPED_ELEMENTS: &[&str] = &[
+    "script",
+    "foreignobject",
+    "iframe",
+    "embed",
+    "object",
+    "handler",
+    "listener",
+    "meta",
+    "link",
+    "base",
+];
+
+/// Attributes that load a URL, only local references are kept in them.
+const URL_ATTRIBUTES: &[&str] = &[
+    "href",
+    "src",
+    "srcset",
+    "action",
+    "formaction",
+    "poster",
+    "background",
+    "data",
+];
+
+/// Animation elements, they are removed when they animate a link.
+const ANIMATION_ELEMENTS: &[&str] = &["animate", "set", "animatemotion", "animatetransform"];
+
+/// Removes scripts, event handlers, `javascript:` URLs, external references and
+/// `foreignObject` from the SVG at `path` in place.
+///
+/// SVGs that aren't well-formed XML with an `<svg>` root are rejected.
+pub(crate) fn sanitize_file(path: &str) -> Result<(), MyError> {
+    let data = std::fs::read(path)?;
+    let input =
+        std::str::from_utf8(&data).map_err(|_| ErrorBadRequest("Invalid SVG: not UTF-8"))?;
+    let sanitized = sanitize(input).map_err(|e| {
+        debug!("svg parsing failed: {}", e);
+        ErrorBadRequest(format!("Invalid SVG: {}", e))
+    })?;
+    std::fs::write(path, sanitized)?;
+    Ok(())
+}
+
+/// Element name without its namespace prefix, lowercased.
+fn local_name(name: &str) -> String {
+    name.rsplit(':').next().unwrap_or(name).to_ascii_lowercase()
+}
+
+/// Lowercases a URL and removes the whitespace and control characters browsers
+/// ignore, so `java\tscript:` can't hide.
+fn normalize_url(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+fn is_script_url(value: &str) -> bool {
+    let value = normalize_url(value);
+    value.contains("javascript:") || value.contains("vbscript:") || value.contains("data:text/html")
+}
+
+/// Only references to the document itself and embedded raster images are kept.
+fn is_local_url(value: &str) -> bool {
+    let value = normalize_url(value);
+    value.starts_with('#')
+        || ["png", "jpeg", "jpg", "gif", "webp"]
+            .iter()
+            .any(|t| value.starts_with(&format!("data:image/{};", t)))
+}
+
+/// Returns true when CSS loads anything from outside the document. CSS with
+/// escapes is treated as external too, as they could spell either.
+fn css_is_external(css: &str) -> bool {
+    let css = normalize_url(css);
+    css.contains("@import")
+        || css.contains('\\')
+        || css.contains("image-set(")
+        || css.split("url(").skip(1).any(|rest| {
+            let url = rest.trim_start_matches(['"', '\'']);
+            !is_local_url(url)
+        })
+}
+
+/// Decodes the entity and character references of XML text, only the predefined
+/// entities are known as the DTD is not read.
+fn decode_entities(text: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('&') {
+        out.push_str(&rest[..i]);
+        let end = rest[i..]
+            .find(';')
+            .ok_or_else(|| "unterminated entity".to_string())?;
+        let entity = &rest[i + 1..i + end];
+        let c = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(|dec| dec.parse()))
+                .and_then(|code| code.ok())
+                .and_then(char::from_u32)
+                .ok_or_else(|| format!("unknown entity &{};", entity))?,
+        };
+        out.push(c);
+        rest = &rest[i + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+}
+
+/// Name and decoded value of an attribute.
+type Attribute<'a> = (&'a str, String);
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    /// Skips past the next `end`, returning what is before it.
+    fn take_until(&mut self, end: &str) -> Result<&'a str, String> {
+        let rest = self.rest();
+        let i = rest.find(end).ok_or_else(|| format!("missing {}", end))?;
+        self.pos += i + end.len();
+        Ok(&rest[..i])
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn name(&mut self) -> Result<&'a str, String> {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| c.is_whitespace() || matches!(c, '/' | '>' | '='))
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Err("missing name".to_string());
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    /// Parses the attributes of a start tag, returns them and whether the tag is
+    /// self-closing.
+    fn attributes(&mut self) -> Result<(Vec<Attribute<'a>>, bool), String> {
+        let mut attributes: Vec<Attribute> = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            if rest.starts_with("/>") {
+                self.pos += 2;
+                return Ok((attributes, true));
+            }
+            if rest.starts_with('>') {
+                self.pos += 1;
+                return Ok((attributes, false));
+            }
+            if rest.is_empty() {
+                return Err("unterminated tag".to_string());
+            }
+
+            let name = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(format!("attribute {} has no value", name));
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let quote = match self.rest().chars().next() {
+                Some(q @ ('"' | '\'')) => q,
+                _ => return Err(format!("attribute {} is not quoted", name)),
+            };
+            self.pos += 1;
+            let raw = self.take_until(&quote.to_string())?;
+            if raw.contains('<') {
+                return Err(format!("attribute {} contains <", name));
+            }
+            if attributes.iter().any(|(n, _)| *n == name) {
+                return Err(format!("duplicate attribute {}", name));
+            }
+            attributes.push((name, decode_entities(raw)?));
+        }
+    }
+}
+
+/// Returns the text content of the element whose start tag was just parsed, up to
+/// its end tag: text, CDATA sections and the text of nested elements. Comments
+/// are left out.
+fn text_content(input: &str) -> String {
+    let mut parser = Parser { input, pos: 0 };
+    let mut text = String::new();
+    let mut depth = 0;
+    while !parser.rest().is_empty() {
+        let rest = parser.rest();
+        let done = if rest.starts_with("<!--") {
+            parser.pos += 4;
+            parser.take_until("-->").is_err()
+        } else if rest.starts_with("<![CDATA[") {
+            parser.pos += 9;
+            match parser.take_until("]]>") {
+                Ok(cdata) => {
+                    text.push_str(cdata);
+                    false
+                }
+                Err(_) => true,
+            }
+        } else if rest.starts_with("<?") {
+            parser.take_until("?>").is_err()
+        } else if rest.starts_with("</") {
+            depth -= 1;
+            depth < 0 || parser.take_until(">").is_err()
+        } else if rest.starts_with('<') {
+            parser.pos += 1;
+            match parser.name().and_then(|_| parser.attributes()) {
+                Ok((_, self_closing)) => {
+                    if !self_closing {
+                        depth += 1;
+                    }
+                    false
+                }
+                Err(_) => true,
+            }
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            let raw = &rest[..end];
+            text.push_str(&decode_entities(raw).unwrap_or_else(|_| raw.to_string()));
+            parser.pos += end;
+            false
+        };
+        if done {
+            break;
+        }
+    }
+    text
+}
+
+/// Sanitizes an SVG document, see [`sanitize_file`].
+pub(crate) fn sanitize(input: &str) -> Result<String, String> {
+    let mut parser = Parser { input, pos: 0 };
+    let mut out = String::with_capacity(input.len());
+    // open elements and whether they are written to the output
+    let mut stack: Vec<(&str, bool)> = Vec::new();
+    let mut seen_root = false;
+
+    while !parser.rest().is_empty() {
+        let rest = parser.rest();
+        let writing = stack.last().is_some_and(|(_, kept)| *kept);
+
+        if rest.starts_with("<!--") {
+            parser.pos += 4;
+            parser.take_until("-->")?;
+        } else if rest.starts_with("<![CDATA[") {
+            parser.pos += 9;
+            let text = parser.take_until("]]>")?;
+            if stack.is_empty() {
+                return Err("text outside the root element".to_string());
+            }
+            if writing {
+                out.push_str("<![CDATA[");
+                out.push_str(text);
+                out.push_str("]]>");
+            }
+        } else if rest.len() >= 9 && rest[..9].eq_ignore_ascii_case("<!doctype") {
+            // dropped, with its internal subset
+            if seen_root {
+                return Err("doctype after the root element".to_string());
+            }
+            let end = match (rest.find('['), rest.find('>')) {
+                (Some(open), Some(close)) if open < close => {
+                    let subset_end = rest[open..]
+                        .find("]")
+                        .ok_or_else(|| "unterminated doctype".to_string())?;
+                    open + subset_end
+                        + rest[open + subset_end..]
+                            .find('>')
+                            .ok_or_else(|| "unterminated doctype".to_string())?
+                }
+                (_, Some(close)) => close,
+                _ => return Err("unterminated doctype".to_string()),
+            };
+            parser.pos += end + 1;
+        } else if rest.starts_with("<?") {
+            // the XML declaration and processing instructions such as
+            // xml-stylesheet are dropped
+            parser.take_until("?>")?;
+        } else if rest.starts_with("</") {
+            parser.pos += 2;
+            let name = parser.name()?;
+            parser.skip_whitespace();
+            if !parser.rest().starts_with('>') {
+                return Err(format!("invalid end tag {}", name));
+            }
+            parser.pos += 1;
+            match stack.pop() {
+                Some((open, kept)) if open == name => {
+                    if kept {
+                        out.push_str("</");
+                        out.push_str(name);
+                        out.push('>');
+                    }
+                }
+                _ => return Err(format!("unexpected end tag {}", name)),
+            }
+        } else if rest.starts_with('<') {
+            parser.pos += 1;
+            let name = parser.name()?;
+            let (attributes, self_closing) = parser.attributes()?;
+            let local = local_name(name);
+
+            if stack.is_empty() {
+                if seen_root {
+                    return Err("more than one root element".to_string());
+                }
+                if local != "svg" {
+                    return Err("the root element is not svg".to_string());
+                }
+                seen_root = true;
+            }
+
+            let animates_link = ANIMATION_ELEMENTS.contains(&local.as_str())
+                && attributes
+                    .iter()
+                    .any(|(n, v)| local_name(n) == "attributename" && local_name(v) == "href");
+            let external_style =
+                local == "style" && !self_closing && css_is_external(&text_content(parser.rest()));
+            let kept = (stack.is_empty() || writing)
+                && !DROPPED_ELEMENTS.contains(&local.as_str())
+                && !animates_link
+                && !external_style;
+
+            if kept {
+                out.push('<');
+                out.push_str(name);
+                for (attr, value) in &attributes {
+                    let attr_local = local_name(attr);
+                    let dropped = attr_local.starts_with("on")
+                        || is_script_url(value)
+                        || (URL_ATTRIBUTES.contains(&attr_local.as_str()) && !is_local_url(value))
+                        || (attr_local == "style" && css_is_external(value));
+                    if dropped {
+                        debug!("svg: dropping attribute {}", attr);
+                        continue;
+                    }
+                    out.push(' ');
+                    out.push_str(attr);
+                    out.push_str("=\"");
+                    out.push_str(&escape_attribute(value));
+                    out.push('"');
+                }
+                out.push_str(if self_closing { "/>" } else { ">" });
+            } else if stack.is_empty() || writing {
+                debug!("svg: dropping element {}", name);
+            }
+            if !self_closing {
+                stack.push((name, kept));
+            }
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            let text = &rest[..end];
+            parser.pos += end;
+            decode_entities(text)?;
+            if stack.is_empty() {
+                if !text.trim().is_empty() {
+                    return Err("text outside the root element".to_string());
+                }
+            } else if writing {
+                out.push_str(text);
+            }
+        }
+    }
+
+    if let Some((name, _)) = stack.last() {
+        return Err(format!("unclosed element {}", name));
+    }
+    if !seen_root {
+        return Err("no svg element".to_string());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        let input = r##"<?xml version="1.0"?>
+<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd">
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" onload="alert(1)">
+  <script>alert(1)</script>
+  <rect id="r" width="10" height="10" fill="red" onclick="alert(2)"/>
+  <use xlink:href="#r"/>
+  <use href="https://evil.example/sprite.svg#x"/>
+  <a href=" java&#x09;script:alert(3)"><text>Tom &amp; Jerry</text></a>
+  <foreignObject><div xmlns="http://www.w3.org/1999/xhtml">hi</div></foreignObject>
+  <set attributeName="href" to="javascript:alert(4)"/>
+  <style>@import url(https://evil.example/x.css);</style>
+  <style><![CDATA[rect { fill: url(#g) }]]></style>
+</svg>"##;
+        let expected = r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink">
+  
+  <rect id="r" width="10" height="10" fill="red"/>
+  <use xlink:href="#r"/>
+  <use/>
+  <a><text>Tom &amp; Jerry</text></a>
+  
+  
+  
+  <style><![CDATA[rect { fill: url(#g) }]]></style>
+</svg>"##;
+        assert_eq!(sanitize(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_sanitize_hidden() {
+        let input = r##"<svg xmlns:h="http://www.w3.org/1999/xhtml">
+  <style><![CDATA[/* </style> */ @import url(//evil.example/x.css);]]></style>
+  <style><!-- </ -->rect { fill: url(//evil.example/x.svg#g) }</style>
+  <style>rect { background: \75 rl(//evil.example/x.png) }</style>
+  <h:img src="https://evil.example/x.png"/>
+  <h:meta http-equiv="refresh" content="0;url=https://evil.example/"/>
+  <image src="data:image/png;base64,AAAA"/>
+</svg>"##;
+        let expected = r##"<svg xmlns:h="http://www.w3.org/1999/xhtml">
+  
+  
+  
+  <h:img/>
+  
+  <image src="data:image/png;base64,AAAA"/>
+</svg>"##;
+        assert_eq!(sanitize(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_sanitize_invalid() {
+        assert!(sanitize("<svg><g></svg>").is_err());
+        assert!(sanitize("<html><svg/></html>").is_err());
+        assert!(sanitize("<svg/><svg/>").is_err());
+        assert!(sanitize("<svg attr=unquoted/>").is_err());
+        assert!(sanitize("<svg>&xxe;</svg>").is_err());
+        assert!(sanitize("not xml").is_err());
+        assert!(sanitize("<svg/>").is_ok());
+    }
+}
@@ -14,6 +14,9 @@
 /// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 ///
 use crate::crypto::{sign_message, verify_signature};
+use crate::staging::{stage_test_file, test_path};
+use crate::storage::FsStorage;
+use crate::{find_object, move_by_hash};
 
 const TEST_KEY: &[u8] = b"crMwNFYF1cPeFqC16h43viK87zSEqlvt";
 
@@ -30,3 +33,200 @@ fn test_verify_bad_signature() {
     let signature = sign_message(TEST_KEY, b"world");
     assert_eq!(verify_signature(TEST_KEY, b"world2", &signature), false);
 }
+
+#[actix_rt::test]
+async fn test_move_by_hash() {
+    let dir = &test_path("objects");
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir).unwrap();
+    let stage = |data: &[u8]| stage_test_file(&format!("{}/.staging", dir), data);
+    let storage = FsStorage::new(dir);
+
+    let hash = "a9993e364706816aba3e25717850c26c9cd0d89d";
+    assert_eq!(
+        move_by_hash(stage(b"abc"), &storage, "txt", "text/plain")
+            .await
+            .unwrap(),
+        (hash.to_string(), false)
+    );
+    let tmp = stage(b"abc");
+    let path = tmp.path().to_owned();
+    assert_eq!(
+        move_by_hash(tmp, &storage, "txt", "text/plain")
+            .await
+            .unwrap(),
+        (hash.to_string(), true)
+    );
+    assert!(!std::path::Path::new(&path).exists());
+
+    std::fs::write(format!("{}/{}.json", dir, hash), "{}").unwrap();
+    assert_eq!(
+        find_object(&storage, hash, None).await.unwrap(),
+        Some("txt".to_string())
+    );
+    assert_eq!(
+        find_object(&storage, hash, Some("json")).await.unwrap(),
+        Some("json".to_string())
+    );
+    assert_eq!(
+        find_object(&storage, hash, Some("png")).await.unwrap(),
+        None
+    );
+    assert_eq!(
+        find_object(&storage, "0000000000000000000000000000000000000000", None)
+            .await
+            .unwrap(),
+        None
+    );
+
+    // files stored before the index are found by their extension, images before
+    // their sidecar
+    let old = "0000000000000000000000000000000000000001";
+    std::fs::write(format!("{}/{}.json", dir, old), "{}").unwrap();
+    std::fs::write(format!("{}/{}.png", dir, old), "").unwrap();
+    assert_eq!(
+        find_object(&storage, old, None).await.unwrap(),
+        Some("png".to_string())
+    );
+    // a stale entry doesn't find a deleted file
+    std::fs::remove_file(format!("{}/{}.txt", dir, hash)).unwrap();
+    std::fs::remove_file(format!("{}/{}.json", dir, hash)).unwrap();
+    assert_eq!(find_object(&storage, hash, None).await.unwrap(), None);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[actix_web::test]
+async fn test_save_file_in_memory() {
+    use actix_web::{test, web, App};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::idempotency::Idempotency;
+    use crate::progress::Progress;
+    use crate::storage::{MemoryStorage, Storage, Storages};
+    use crate::{nonce, save_file, upload_status, SecretKey};
+
+    // nothing is written to the output directory but the staged uploads
+    let dir = &test_path("memory-app");
+    let storage = Arc::new(MemoryStorage::new(1024));
+    let mut storages = Storages::default();
+    storages.insert(None, dir.clone(), storage.clone());
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(SecretKey("test".to_string())))
+            .app_data(web::Data::new(storages))
+            .app_data(web::Data::new(Idempotency::new(
+                Duration::from_secs(60),
+                100,
+                "X-Client-Id",
+                "test",
+            )))
+            .app_data(web::Data::new(Progress::new(Duration::from_secs(60), 100)))
+            .route("/upload", web::post().to(save_file))
+            .route("/uploads/{id}", web::get().to(upload_status)),
+    )
+    .await;
+    let upload = || {
+        let nonce = nonce::nonce().to_string();
+        let body = "--XYZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"hello.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            hello\r\n\
+            --XYZ--\r\n";
+        test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("X-Signature", sign_message(b"test", nonce.as_bytes())))
+            .insert_header(("X-Nonce", nonce))
+            .insert_header(("Content-Type", "multipart/form-data; boundary=XYZ"))
+            .set_payload(body)
+            .to_request()
+    };
+
+    let hash = "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d";
+    let response: serde_json::Value = test::call_and_read_body_json(&app, upload()).await;
+    assert_eq!(response["sha1"], hash);
+    assert_eq!(response["existed"], false);
+
+    // the status needs the signature of its id, only the file is counted
+    let id = response["upload_id"].as_str().unwrap();
+    let status = |signature: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/uploads/{}?signature={}", id, signature))
+            .to_request()
+    };
+    let response = test::call_service(&app, status("00")).await;
+    assert_eq!(response.status(), 400);
+    let signature = sign_message(b"test", format!("upload:{}", id).as_bytes());
+    let response: serde_json::Value = test::call_and_read_body_json(&app, status(&signature)).await;
+    assert_eq!(response["state"], "done");
+    assert_eq!(response["bytes_received"], 5);
+
+    let response: serde_json::Value = test::call_and_read_body_json(&app, upload()).await;
+    assert_eq!(response["existed"], true);
+
+    assert_eq!(
+        storage.get(&format!("{}.txt", hash)).await.unwrap(),
+        Some(b"hello".to_vec())
+    );
+    assert_eq!(
+        find_object(&*storage, hash, None).await.unwrap(),
+        Some("txt".to_string())
+    );
+    assert!(!std::path::Path::new(&format!("{}/{}.txt", dir, hash)).exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[actix_web::test]
+async fn test_sniffed_image_is_validated() {
+    use actix_web::web::Bytes;
+    use actix_web::ResponseError;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::checksum::Verifier;
+    use crate::progress::Progress;
+    use crate::quarantine::Origin;
+    use crate::storage::MemoryStorage;
+    use crate::upload::{store_stream, OutDir};
+
+    let dir = &test_path("sniffed-image");
+    let out_dir = &OutDir::new(dir.clone(), None, Arc::new(MemoryStorage::new(1 << 20)));
+    let progress = Progress::new(Duration::from_secs(60), 100);
+    let req = actix_web::test::TestRequest::default().to_http_request();
+    let store = |data: Vec<u8>| {
+        let tracker = progress.start(&req).unwrap();
+        async move {
+            let chunks = futures::stream::iter(vec![Ok::<_, String>(Bytes::from(data))]);
+            let octet_stream = Some(mime_guess::mime::APPLICATION_OCTET_STREAM);
+            store_stream(
+                out_dir,
+                "upload",
+                octet_stream,
+                Verifier::none(),
+                &tracker,
+                &Origin::default(),
+                chunks,
+            )
+            .await
+        }
+    };
+
+    // a PNG is stored as one even when it is declared as bytes
+    let stored = store(std::fs::read("img/rantang.png").unwrap())
+        .await
+        .unwrap();
+    assert_eq!(stored.mime_type, "image/png");
+    assert_eq!(stored.extension, "png");
+    assert_eq!(stored.format.as_deref(), Some("png"));
+
+    // and is decoded, so a broken one is rejected
+    let mut broken = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+    broken.extend_from_slice(&[0xff; 64]);
+    let error = store(broken).await.err().unwrap();
+    assert!(error.error_response().status().is_client_error());
+
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,680 @@
+/// MIT License
+///
+/// Copyright (c) 2023 Robin Syihab <r@nu.id>
+///
+/// Permission is hereby granted, free of charge, to any person obtaining a copy of this software and associated documentation files (the "Software"),
+/// to deal in the Software without restriction, including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+/// and/or sell copies of the Software and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+///
+/// The above copyright notice and this permission notice shall be included in all copies or substantial portions of the Software.
+///
+/// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES
+/// OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+/// BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF
+/// OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+///
+use actix_web::error::{ErrorBadRequest, ErrorPayloadTooLarge};
+use actix_web::web::{self, Bytes};
+use actix_web::ResponseError;
+use anyhow::anyhow;
+use futures::{Stream, StreamExt};
+use image::ImageFormat;
+use log::{debug, warn};
+use serde_json::json;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+use crate::checksum::Verifier;
+use crate::config::{self, format_size, SizeLimits};
+use crate::crypto;
+use crate::error::MyError;
+use crate::imaging::{self, ImageInfo, ImageLimits, Transcode, Variant, VariantSpec};
+use crate::pending::{PendingConfig, PendingUpload};
+use crate::phash::{self, Match, PhashConfig, PhashIndex};
+use crate::pipeline::{self, Step, Upload};
+use crate::placeholder::{self, Placeholder, PlaceholderConfig};
+use crate::progress::{State, Tracker};
+use crate::quarantine::{self, Origin, Record};
+use crate::scan::ScanConfig;
+use crate::sniff::{self, MimeFilter, MismatchPolicy};
+use crate::staging::{self, TmpFile};
+use crate::storage::{self, FsStorage, Storage};
+
+pub(crate) const SVG_MIME_TYPE: &str = "image/svg+xml";
+
+/// Output directory an upload is written to, resolved from the `X-Dir-Index` header.
+pub(crate) struct OutDir {
+    pub path: String,
+    pub index: Option<String>,
+    /// Where stored files end up, the directory itself by default.
+    pub storage: Arc<dyn Storage>,
+    /// Directory uploads are written to before they are moved into `path`.
+    pub staging: String,
+    pub limits: SizeLimits,
+    pub image_limits: ImageLimits,
+    pub image_formats: Vec<ImageFormat>,
+    pub transcode: Option<Transcode>,
+    pub variants: Vec<VariantSpec>,
+    pub strip_metadata: bool,
+    pub redact_gps: bool,
+    pub placeholder: Option<PlaceholderConfig>,
+    pub phash: Option<PhashConfig>,
+    pub mime_mismatch: MismatchPolicy,
+    pub mime_filter: MimeFilter,
+    pub scan: Option<ScanConfig>,
+    /// Directory rejected uploads are moved to.
+    pub quarantine: String,
+    /// Keeps every upload rejected by a check in quarantine, not only infected ones.
+    pub quarantine_rejected: bool,
+    /// Checks and conversions applied to uploads before they are stored.
+    pub pipeline: Vec<Step>,
+    /// Uploads wait in a pending directory until they are confirmed.
+    pub pending: Option<PendingConfig>,
+}
+
+impl OutDir {
+    pub(crate) fn new(path: String, index: Option<String>, storage: Arc<dyn Storage>) -> Self {
+        let limits = SizeLimits::from_env(index.as_deref());
+        debug!("size limits: {:?}", limits);
+        let image_limits = ImageLimits::from_env(index.as_deref());
+        debug!("image limits: {:?}", image_limits);
+        let image_formats = imaging::accepted_formats_from_env(index.as_deref());
+        debug!("image formats: {:?}", image_formats);
+        let transcode = Transcode::from_env(index.as_deref());
+        debug!("transcode: {:?}", transcode);
+        let variants = imaging::variants_from_env(index.as_deref());
+        debug!("variants: {:?}", variants);
+        let strip_metadata = config::dir_flag("STRIP_METADATA", index.as_deref());
+        let redact_gps = config::dir_flag("REDACT_GPS", index.as_deref());
+        let placeholder = PlaceholderConfig::from_env(index.as_deref());
+        debug!("placeholder: {:?}", placeholder);
+        let phash = PhashConfig::from_env(index.as_deref());
+        debug!("phash: {:?}", phash);
+        let mime_mismatch = MismatchPolicy::from_env(index.as_deref());
+        let mime_filter = MimeFilter::from_env(index.as_deref());
+        debug!("mime filter: {:?}", mime_filter);
+        let scan = ScanConfig::from_env(index.as_deref());
+        debug!("scan: {:?}", scan);
+        let staging = staging::staging_dir(&path, index.as_deref());
+        let quarantine = quarantine::quarantine_dir(&path, index.as_deref());
+        let quarantine_rejected = config::dir_flag("QUARANTINE", index.as_deref());
+        debug!("storage: {}", storage.name());
+        let pending = PendingConfig::from_env(&path, index.as_deref());
+        debug!("pending: {:?}", pending);
+        let mut out_dir = Self {
+            staging,
+            path,
+            index,
+            storage,
+            limits,
+            image_limits,
+            image_formats,
+            transcode,
+            variants,
+            strip_metadata,
+            redact_gps,
+            placeholder,
+            phash,
+            mime_mismatch,
+            mime_filter,
+            scan,
+            quarantine,
+            quarantine_rejected,
+            pipeline: Vec::new(),
+            pending,
+        };
+        out_dir.pipeline = pipeline::from_env(&out_dir);
+        debug!("pipeline: {:?}", out_dir.pipeline);
+        out_dir
+    }
+}
+
+/// Error returned for a file larger than `max_size`.
+pub(crate) fn too_large(max_size: usize) -> MyError {
+    ErrorPayloadTooLarge(format!("File size exceeds {} limit", format_size(max_size))).into()
+}
+
+/// Rejects a file of `length` bytes that exceeds `max_size`.
+pub(crate) fn check_size(length: u64, max_size: usize) -> Result<(), MyError> {
+    if length > max_size as u64 {
+        return Err(too_large(max_size));
+    }
+    Ok(())
+}
+
+/// Describes a file after it has been moved into its output directory.
+pub(crate) struct Stored {
+    /// Id the progress of the upload is tracked with.
+    pub upload_id: String,
+    pub sha1: String,
+    /// A file with the same content was already stored.
+    pub existed: bool,
+    pub extension: String,
+    pub mime_type: String,
+    /// Type the client uploaded the file as.
+    pub declared_mime_type: String,
+    /// Type detected from the content of the file.
+    pub detected_mime_type: String,
+    /// The detected type doesn't match the declared one.
+    pub mime_mismatch: bool,
+    /// Detected format of images.
+    pub format: Option<String>,
+    /// Dimensions, transparency and frames of images.
+    pub image: Option<ImageInfo>,
+    /// EXIF of the upload as it was received, before metadata was stripped.
+    pub exif: Option<serde_json::Map<String, serde_json::Value>>,
+    /// The upload as it was received, when it was converted and kept.
+    pub original: Option<Original>,
+    /// Resized variants of images.
+    pub variants: Vec<Variant>,
+    /// Placeholders shown while the image loads.
+    pub placeholder: Option<Placeholder>,
+    /// Perceptual hash of images and the near-duplicates already stored.
+    pub phash: Option<(String, Vec<Match>)>,
+    /// The upload waiting for its confirmation.
+    pub pending: Option<Pending>,
+}
+
+/// Pending upload, see [`PendingUpload`], confirmed with the token of its id.
+pub(crate) struct Pending {
+    pub id: String,
+    /// Unix time after which the upload is deleted.
+    pub expires_at: u64,
+}
+
+/// Original of a converted image.
+pub(crate) struct Original {
+    pub sha1: String,
+    pub extension: String,
+}
+
+/// Determines the MIME type of an upload.
+///
+/// Browsers send canvas and clipboard data with the generic `blob` filename, in
+/// that case the client declared content type is used, otherwise the type is
+/// guessed from the filename.
+pub(crate) fn resolve_mime_type(
+    filename: &str,
+    declared: Option<&str>,
+) -> Option<mime_guess::Mime> {
+    if filename == "blob" {
+        let content_type = declared.unwrap_or("image/jpg");
+        debug!("content_type: {}", content_type);
+        content_type.parse().ok()
+    } else {
+        Some(mime_guess::from_path(filename).first_or_octet_stream())
+    }
+}
+
+/// Keeps an upload rejected with `error` in quarantine when its output directory
+/// asks for it with `QUARANTINE`, infected uploads always are.
+///
+/// Returns `error`.
+async fn reject(out_dir: &OutDir, tmp: TmpFile, mut record: Record, error: MyError) -> MyError {
+    let status = error.error_response().status();
+    if !status.is_client_error() || !(out_dir.quarantine_rejected || record.signature.is_some()) {
+        return error;
+    }
+    record.reason = error.to_string();
+    record.status = status.as_u16();
+    let dir = out_dir.quarantine.clone();
+    match web::block(move || quarantine::quarantine(tmp, &dir, record)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => warn!("Failed to quarantine upload: {}", e),
+        Err(e) => warn!("Failed to quarantine upload: {}", e),
+    }
+    error
+}
+
+/// Whether an upload rejected by the pipeline of `out_dir` can be quarantined
+/// after a step changed it.
+fn may_quarantine(out_dir: &OutDir) -> bool {
+    (out_dir.quarantine_rejected || out_dir.scan.is_some())
+        && out_dir
+            .pipeline
+            .iter()
+            .any(|step| matches!(step, Step::Process(_)))
+}
+
+/// Streams the chunks of an upload into a temporary file inside `out_dir`, checks
+/// it and renames it by its SHA1 hash.
+///
+/// This is shared by every upload source (multipart, JSON/base64) so they are
+/// subject to the same size limit and format validation.
+pub(crate) async fn store_stream<S, E>(
+    out_dir: &OutDir,
+    filename: &str,
+    mime_type: Option<mime_guess::Mime>,
+    mut verifier: Verifier,
+    tracker: &Tracker,
+    origin: &Origin,
+    mut stream: S,
+) -> Result<Stored, MyError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    // filename cannot contains /
+    if filename.contains('/') {
+        return Err(ErrorBadRequest("Invalid filename.").into());
+    }
+
+    debug!("mime_type: {:?}", mime_type);
+
+    // reject what the directory doesn't accept before reading it
+    if let Some(declared) = mime_type
+        .as_ref()
+        .filter(|a| a.essence_str() != sniff::OCTET_STREAM)
+    {
+        out_dir
+            .mime_filter
+            .check(&declared.essence_str().to_ascii_lowercase())?;
+    }
+
+    // the staged file is deleted on every failure, including a dropped connection
+    let (tmp, f) = TmpFile::create(&out_dir.staging, filename)?;
+    let mut f = tokio::fs::File::from_std(f);
+
+    // SVGs are sanitized instead of decoded
+    let is_image = mime_type
+        .as_ref()
+        .is_some_and(|a| a.type_().as_str() == "image" && a.essence_str() != SVG_MIME_TYPE);
+    let max_size = out_dir.limits.for_mime(mime_type.as_ref());
+    let mut format: Option<ImageFormat> = None;
+    let mut head: Vec<u8> = Vec::with_capacity(imaging::HEAD_LEN);
+    let mut length = 0u64;
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| anyhow!("{}", e))?;
+        length += chunk.len() as u64;
+        check_size(length, max_size)?;
+        verifier.update(&chunk);
+        f.write_all(&chunk).await?;
+        if head.len() < imaging::HEAD_LEN {
+            let n = (imaging::HEAD_LEN - head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..n]);
+            // reject unwanted images as soon as their format is known
+            if is_image && head.len() == imaging::HEAD_LEN {
+                format = Some(imaging::detect_format(&head, &out_dir.image_formats)?);
+            }
+        }
+    }
+    f.flush().await?;
+    drop(f);
+
+    // images are checked by decoding them, other files by their magic bytes
+    let declared = mime_type
+        .as_ref()
+        .map_or(sniff::OCTET_STREAM.to_string(), |a| {
+            a.essence_str().to_ascii_lowercase()
+        });
+    // what is known of a rejected upload when it is quarantined
+    let record = |detected: Option<&str>, extension: Option<&str>| Record {
+        filename: filename.to_owned(),
+        size: length,
+        dir_index: out_dir.index.clone(),
+        declared_mime_type: Some(declared.clone()),
+        detected_mime_type: detected.map(str::to_owned),
+        extension: extension.map(str::to_owned),
+        origin: origin.clone(),
+        ..Default::default()
+    };
+
+    // the data is checked against the client's checksums before anything else
+    tracker.state(State::Validating);
+    let checked = verifier.verify().and_then(|_| {
+        if is_image && format.is_none() {
+            format = Some(imaging::detect_format(&head, &out_dir.image_formats)?);
+        }
+        Ok(())
+    });
+    if let Err(e) = checked {
+        return Err(reject(out_dir, tmp, record(None, None), e).await);
+    }
+    let detected = match format {
+        Some(format) => imaging::format_mime_type(format),
+        None => {
+            let path = tmp.path().to_owned();
+            web::block(move || sniff::sniff_file(&path))
+                .await
+                .map_err(actix_web::Error::from)??
+        }
+    };
+    let mime_mismatch = format.is_none() && !sniff::is_compatible(&declared, detected);
+    debug!("declared: {}, detected: {}", declared, detected);
+    // content that is an image is checked as one, whatever its declared type
+    if format.is_none() && detected.starts_with("image/") && detected != SVG_MIME_TYPE {
+        let sniffed = imaging::detect_format(&head, &out_dir.image_formats).and_then(|sniffed| {
+            check_size(
+                length,
+                out_dir.limits.for_mime(detected.parse().ok().as_ref()),
+            )?;
+            Ok(sniffed)
+        });
+        match sniffed {
+            Ok(sniffed) => format = Some(sniffed),
+            Err(e) => return Err(reject(out_dir, tmp, record(Some(detected), None), e).await),
+        }
+    }
+    // flagged files are stored as what they are
+    let content_type = if mime_mismatch || declared == sniff::OCTET_STREAM {
+        Some(detected)
+    } else {
+        None
+    };
+    let mime_type_stored = match (format, content_type) {
+        (Some(format), _) => imaging::format_mime_type(format).to_string(),
+        (None, Some(content_type)) => content_type.to_string(),
+        (None, None) => declared.clone(),
+    };
+
+    let extension = match format {
+        Some(format) => imaging::format_extension(format).to_string(),
+        None if mime_mismatch => sniff::extension(detected).unwrap_or("bin").to_string(),
+        None => {
+            // get extension from the filename
+            Path::new(filename)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| ext.to_string())
+                .or_else(|| {
+                    mime_type
+                        .as_ref()
+                        .and_then(mime_guess::get_mime_extensions)
+                        .and_then(|ext| ext.first().map(|ext| ext.to_string()))
+                })
+                .or_else(|| content_type.and_then(sniff::extension).map(str::to_string))
+                .unwrap_or_else(|| "jpg".to_string())
+        }
+    };
+    debug!("extension: {}", extension);
+
+    // uploads waiting for their confirmation are kept apart until then
+    let pending = match out_dir.pending.clone() {
+        Some(config) => Some(
+            web::block(move || PendingUpload::create(&config))
+                .await
+                .map_err(actix_web::Error::from)??,
+        ),
+        None => None,
+    };
+    let storage: Arc<dyn Storage> = match &pending {
+        Some(pending) => Arc::new(FsStorage::new(&pending.dir)),
+        None => out_dir.storage.clone(),
+    };
+
+    let mut upload = Upload {
+        tmp,
+        storage: storage.clone(),
+        length,
+        declared_mime_type: declared.clone(),
+        detected_mime_type: detected,
+        mime_mismatch,
+        mime_type: mime_type_stored,
+        format,
+        extension,
+        exif: None,
+        original: None,
+        infected: None,
+    };
+    // conversions change the staged file, a copy of it as received is kept until
+    // the pipeline passed in case it is quarantined
+    let received = if may_quarantine(out_dir) {
+        let received = upload.tmp.sibling("received");
+        let (from, to) = (upload.tmp.path().to_owned(), received.path().to_owned());
+        web::block(move || std::fs::copy(from, to))
+            .await
+            .map_err(actix_web::Error::from)??;
+        Some(received)
+    } else {
+        None
+    };
+    let received_extension = upload.extension.clone();
+    // checks and conversions of the output directory, see `PIPELINE`
+    if let Err(e) = pipeline::run(&out_dir.pipeline, &mut upload, tracker).await {
+        let record = Record {
+            signature: upload.infected,
+            ..record(Some(upload.detected_mime_type), Some(&received_extension))
+        };
+        let tmp = received.unwrap_or(upload.tmp);
+        return Err(reject(out_dir, tmp, record, e).await);
+    }
+    drop(received);
+    let Upload {
+        tmp,
+        declared_mime_type: declared,
+        mime_type,
+        format,
+        extension,
+        exif,
+        original,
+        ..
+    } = upload;
+
+    tracker.state(State::Processing);
+
+    // the files derived from the upload are made while it is staged, then stored
+    // along with it
+    let path = tmp.path().to_owned();
+    let sha1 = web::block(move || crypto::get_sha1_file(&mut File::open(path)?))
+        .await
+        .map_err(actix_web::Error::from)??;
+
+    let mut image = None;
+    if let Some(format) = format {
+        let path = tmp.path().to_owned();
+        image = Some(
+            web::block(move || imaging::image_info(&path, format))
+                .await
+                .map_err(actix_web::Error::from)??,
+        );
+    }
+
+    let mut variants = Vec::new();
+    let mut variant_files = Vec::new();
+    let mut placeholder = None;
+    let mut phash = None;
+    // AVIF can't be decoded
+    if let Some(format) = format.filter(|f| *f != ImageFormat::Avif) {
+        if !out_dir.variants.is_empty() || out_dir.placeholder.is_some() || out_dir.phash.is_some()
+        {
+            let quality = out_dir.transcode.as_ref().map_or(80, |t| t.quality);
+            let (path, hash, specs) = (
+                tmp.path().to_owned(),
+                sha1.clone(),
+                out_dir.variants.clone(),
+            );
+            variant_files = specs.iter().map(|spec| tmp.sibling(&spec.name)).collect();
+            // pending uploads are indexed once they are confirmed
+            let (index_dir, insert) = (out_dir.path.clone(), pending.is_none());
+            let (placeholder_config, phash_config) =
+                (out_dir.placeholder.clone(), out_dir.phash.clone());
+            (variants, placeholder, phash) = web::block(move || -> Result<_, MyError> {
+                let image = imaging::open_image(&path, format)?;
+                let variants =
+                    imaging::generate_variants(&image, &path, &hash, format, &specs, quality)?;
+                let placeholder = placeholder_config
+                    .map(|config| placeholder::generate(&image, &config))
+                    .transpose()?;
+                let phash = match phash_config {
+                    Some(config) => {
+                        let phash = phash::dhash(&image);
+                        let index = PhashIndex::open(&index_dir);
+                        let matches = index.search(phash, config.max_distance, Some(&hash))?;
+                        if insert {
+                            index.insert(phash, &hash)?;
+                        }
+                        Some((phash::to_hex(phash), matches))
+                    }
+                    None => None,
+                };
+                Ok((variants, placeholder, phash))
+            })
+            .await
+            .map_err(actix_web::Error::from)??;
+        }
+    }
+
+    // keep the placeholders next to the image, so they can be looked up later
+    let mut sidecar_file = None;
+    if let (Some(placeholder), Some(info)) = (&placeholder, &image) {
+        let sidecar = json!({
+            "sha1": sha1,
+            "extension": extension,
+            "mime_type": mime_type,
+            "format": format.map(imaging::format_name),
+            "width": info.width,
+            "height": info.height,
+            "has_alpha": info.has_alpha,
+            "frame_count": info.frame_count,
+            "placeholder": placeholder,
+        });
+        let file = tmp.sibling("json");
+        let (path, data) = (
+            file.path().to_owned(),
+            serde_json::to_vec_pretty(&sidecar).map_err(|e| anyhow!(e))?,
+        );
+        web::block(move || std::fs::write(path, data))
+            .await
+            .map_err(actix_web::Error::from)??;
+        sidecar_file = Some(file);
+    }
+
+    // the upload itself goes last, so whatever finds it finds its variants and its
+    // index entry too
+    let key = format!("{}.{}", sha1, extension);
+    let index = tmp.sibling("index");
+    storage::stage_index(&index, &extension).await?;
+    // variants are in the format of the upload
+    let mut files: Vec<(TmpFile, String, &str)> = variants
+        .iter()
+        .zip(variant_files)
+        .map(|(variant, file)| (file, variant.file.clone(), mime_type.as_str()))
+        .collect();
+    if let Some(file) = sidecar_file {
+        files.push((file, format!("{}.json", sha1), "application/json"));
+    }
+    files.push((
+        index,
+        storage::index_key(&sha1),
+        storage::INDEX_CONTENT_TYPE,
+    ));
+    files.push((tmp, key.clone(), mime_type.as_str()));
+    let mut added = Vec::new();
+    let mut existed = false;
+    for (file, name, content_type) in files {
+        match storage.put(file, &name, content_type).await {
+            Ok(found) => {
+                // the original is the last file, whether it existed is reported
+                if !found {
+                    added.push(name);
+                }
+                existed = found;
+            }
+            Err(e) => {
+                // a retry starts over rather than finding the files of a partial upload
+                for name in added.iter().rev() {
+                    if let Err(e) = storage.delete(name).await {
+                        warn!("Failed to delete {}: {}", name, e);
+                    }
+                }
+                return Err(e.into());
+            }
+        }
+    }
+    if pending.is_some() {
+        existed = out_dir.storage.exists(&key).await?;
+    }
+
+    let pending = match pending {
+        Some(upload) => {
+            let (id, expires_at) = (upload.id.clone(), upload.expires_at);
+            let (hash, ext, phash) = (
+                sha1.clone(),
+                extension.clone(),
+                phash.as_ref().map(|(a, _)| a.clone()),
+            );
+            web::block(move || upload.commit(&hash, &ext, phash.as_deref()))
+                .await
+                .map_err(actix_web::Error::from)??;
+            Some(Pending { id, expires_at })
+        }
+        None => None,
+    };
+
+    tracker.done(&sha1);
+    Ok(Stored {
+        upload_id: tracker.id.clone(),
+        sha1,
+        existed,
+        extension,
+        mime_type,
+        declared_mime_type: declared,
+        detected_mime_type: detected.to_string(),
+        mime_mismatch,
+        format: format.map(|f| imaging::format_name(f).to_string()),
+        image,
+        exif,
+        original,
+        variants,
+        placeholder,
+        phash,
+        pending,
+    })
+}
+
+/// Finds the images in `out_dir` that are near-duplicates of the image streamed in
+/// the request body, without storing it.
+///
+/// Returns the perceptual hash of the image and the matches, closest first.
+pub(crate) async fn search_similar<S, E>(
+    out_dir: &OutDir,
+    max_distance: u32,
+    mut stream: S,
+) -> Result<(String, Vec<Match>), MyError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let (tmp, f) = TmpFile::create(&out_dir.staging, "search")?;
+    let mut f = tokio::fs::File::from_std(f);
+    let max_size = out_dir.limits.for_mime(Some(&mime_guess::mime::IMAGE_STAR));
+
+    let mut head: Vec<u8> = Vec::with_capacity(imaging::HEAD_LEN);
+    let mut length = 0u64;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| anyhow!("{}", e))?;
+        length += chunk.len() as u64;
+        check_size(length, max_size)?;
+        f.write_all(&chunk).await?;
+        let n = (imaging::HEAD_LEN - head.len()).min(chunk.len());
+        head.extend_from_slice(&chunk[..n]);
+    }
+    f.flush().await?;
+    drop(f);
+
+    // AVIF can't be decoded
+    let format = match imaging::detect_format(&head, &out_dir.image_formats)? {
+        ImageFormat::Avif => return Err(ErrorBadRequest("AVIF images can't be searched").into()),
+        format => format,
+    };
+
+    let (path, dir, limits) = (
+        tmp.path().to_owned(),
+        out_dir.path.clone(),
+        out_dir.image_limits.clone(),
+    );
+    let result = web::block(move || -> Result<_, MyError> {
+        imaging::validate_image(&path, format, &limits)?;
+        let hash = phash::dhash(&imaging::open_image(&path, format)?);
+        let matches = PhashIndex::open(&dir).search(hash, max_distance, None)?;
+        Ok((phash::to_hex(hash), matches))
+    })
+    .await
+    .map_err(actix_web::Error::from)?;
+
+    drop(tmp);
+    result
+}